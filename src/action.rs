@@ -1,7 +1,6 @@
 use std::time::Duration;
 
-use bevy::{math::*, prelude::*};
-use bevy_scene_hook::{HookedSceneBundle, SceneHook};
+use bevy::{math::*, prelude::*, utils::HashMap};
 use iyes_loopless::{
     prelude::FixedTimesteps,
     state::{CurrentState, NextState},
@@ -13,12 +12,16 @@ use int_enum::IntEnum;
 
 use crate::{
     assets::ModelAssets,
-    board::GameBoard,
+    board::{Footprint, GameBoard},
+    economy::Economy,
     items::{
-        spawn_factory, spawn_outgoing_hats, Blobby, InitialPlayerResources, Item, Path,
-        ResourcesAvailableToPlayer, Sellable,
+        insert_scene, spawn_charging_station, spawn_factory, spawn_generic_factory,
+        spawn_outgoing_hats, Blobby, BlobbyState, ChargingStation, Dropoff, InitialPlayerResources,
+        Item, Level, Order, Orders, OutputResource, Path, Pickup, PlasticReceiver,
+        ResourcesAvailableToPlayer, Retoolable, Sellable, SupplyRoutes, MAX_LEVEL,
     },
-    player::{PlayerState, Resources, GAMESETTINGS, R},
+    player::{GameSettings, PlayerState, Resources, R},
+    replay::ReplayHeader,
     schedule::TIMESTEP_MILLI,
     PausedState, RestartGame,
 };
@@ -28,15 +31,29 @@ pub fn process_actions(
     mut action_queue: ResMut<ActionQueue>,
     mut player: ResMut<PlayerState>,
     mut restart: ResMut<RestartGame>,
-    model_assets: Res<ModelAssets>,
+    model_assets: Option<Res<ModelAssets>>,
     mut b: ResMut<GameBoard>,
     //pref: Res<Preferences>,
-    mut time_step_info: ResMut<FixedTimesteps>,
+    mut time_step_info: Option<ResMut<FixedTimesteps>>,
     paused_state: Res<CurrentState<PausedState>>,
     mut game_recorder: ResMut<GameRecorder>,
-    mut blobbies: Query<&mut Blobby>,
+    (rules, economy, mut routes): (Res<GameSettings>, Res<Economy>, ResMut<SupplyRoutes>),
+    mut blobbies: Query<(&Transform, &mut Blobby, &mut Orders)>,
     mut resources_for_player: Query<&mut Resources, With<ResourcesAvailableToPlayer>>,
-    sellable: Query<Entity, With<Sellable>>,
+    (sellable, mut levels): (
+        Query<Entity, With<Sellable>>,
+        Query<(&mut Level, &Item, &OutputResource, Option<&mut Retoolable>)>,
+    ),
+    (pickups, dropoffs): (Query<&Footprint, With<Pickup>>, Query<(), With<Dropoff>>),
+    sites: Query<
+        (
+            &Footprint,
+            Option<&OutputResource>,
+            Option<&PlasticReceiver>,
+            Option<&ChargingStation>,
+        ),
+        Or<(With<Dropoff>, With<PlasticReceiver>)>,
+    >,
     init_player_res: Query<Entity, With<InitialPlayerResources>>,
 ) {
     if game_recorder.play {
@@ -53,6 +70,7 @@ pub fn process_actions(
     #[allow(unused_assignments)]
     #[allow(unused_mut)]
     let mut debug_build = false;
+    let model_assets = model_assets.as_deref();
 
     #[cfg(debug_assertions)]
     {
@@ -73,6 +91,7 @@ pub fn process_actions(
                             {
                                 *resources = resources.sum(&item_res);
                             }
+                            routes.remove_all(entity);
                             b.destroy(&mut com, idx);
                         }
                     }
@@ -80,13 +99,19 @@ pub fn process_actions(
             }
             Action::GameSpeedDec => {
                 player.time_multiplier = (player.time_multiplier - 0.1).max(0.1);
-                time_step_info.single_mut().step =
-                    Duration::from_millis((TIMESTEP_MILLI as f64 / player.time_multiplier) as u64)
+                if let Some(time_step_info) = &mut time_step_info {
+                    time_step_info.single_mut().step = Duration::from_millis(
+                        (TIMESTEP_MILLI as f64 / player.time_multiplier) as u64,
+                    )
+                }
             }
             Action::GameSpeedInc => {
                 player.time_multiplier = (player.time_multiplier + 0.1).min(10.0);
-                time_step_info.single_mut().step =
-                    Duration::from_millis((TIMESTEP_MILLI as f64 / player.time_multiplier) as u64)
+                if let Some(time_step_info) = &mut time_step_info {
+                    time_step_info.single_mut().step = Duration::from_millis(
+                        (TIMESTEP_MILLI as f64 / player.time_multiplier) as u64,
+                    )
+                }
             }
             Action::GamePause => {
                 if *paused_state == CurrentState(PausedState::Paused) {
@@ -115,22 +140,165 @@ pub fn process_actions(
                 }
             }
             Action::MoveBlobby(x, y, id) => {
-                for mut blobby in &mut blobbies {
+                for (_, mut blobby, mut orders) in &mut blobbies {
                     if *id == blobby.id {
+                        orders.queue.clear();
                         blobby.dest = Some(ivec2(*x as i32, *y as i32));
+                        // Sent somewhere while charging, it goes there once charged
+                        if blobby.charger.is_some() {
+                            blobby.dest_before_charge = blobby.dest;
+                        }
+                        blobby.resource_pile = None;
+                        blobby.drop_off = None;
+                        blobby.auto = false;
+                        blobby.job = None;
+                    }
+                }
+            }
+            Action::AssignPile(x, y, id) => {
+                let idx = b.ls_to_idx(ivec2(*x as i32, *y as i32));
+                if let Some(entity) = b.board[idx] {
+                    if pickups.get(entity).is_ok() {
+                        for (_, mut blobby, mut orders) in &mut blobbies {
+                            if *id == blobby.id {
+                                orders.queue.clear();
+                                blobby.resource_pile = Some(entity);
+                                blobby.auto = false;
+                                blobby.job = None;
+                            }
+                        }
+                    }
+                }
+            }
+            Action::UnassignPile(id) => {
+                // The blobby finishes walking to its current destination and then idles
+                for (_, mut blobby, mut orders) in &mut blobbies {
+                    if *id == blobby.id {
+                        orders.queue.clear();
                         blobby.resource_pile = None;
                         blobby.drop_off = None;
+                        blobby.auto = false;
+                        blobby.job = None;
+                    }
+                }
+            }
+            Action::SetAuto(on, id) => {
+                // Ids start at 1 and stop at 255, so id 0 is never given to a blobby and
+                // applies to all of them
+                for (_, mut blobby, mut orders) in &mut blobbies {
+                    if *id == 0 || *id == blobby.id {
+                        blobby.auto = *on != 0;
+                        blobby.job = None;
+                        if blobby.auto {
+                            blobby.resource_pile = None;
+                            blobby.drop_off = None;
+                            orders.queue.clear();
+                        }
+                    }
+                }
+            }
+            Action::QueueMove(x, y, id) => {
+                let order = Order::Move(ivec2(*x as i32, *y as i32));
+                queue_order(&mut blobbies, *id, order);
+            }
+            Action::QueuePickup(x, y, id) => {
+                let idx = b.ls_to_idx(ivec2(*x as i32, *y as i32));
+                if let Some(entity) = b.board[idx] {
+                    if pickups.get(entity).is_ok() {
+                        queue_order(&mut blobbies, *id, Order::Pickup(entity));
+                    }
+                }
+            }
+            Action::QueueDropoff(x, y, id) => {
+                let idx = b.ls_to_idx(ivec2(*x as i32, *y as i32));
+                if let Some(entity) = b.board[idx] {
+                    if dropoffs.get(entity).is_ok() {
+                        queue_order(&mut blobbies, *id, Order::Dropoff(entity));
+                    }
+                }
+            }
+            Action::SetPatrol(on, id) => {
+                for (_, blobby, mut orders) in &mut blobbies {
+                    if *id == blobby.id {
+                        orders.patrol = *on != 0;
+                    }
+                }
+            }
+            Action::RouteFrom(x, y, priority) => {
+                let idx = b.ls_to_idx(ivec2(*x as i32, *y as i32));
+                routes.pending = b.board[idx]
+                    .filter(|entity| pickups.get(*entity).is_ok())
+                    .map(|entity| (entity, *priority));
+            }
+            Action::RouteTo(x, y) => {
+                let idx = b.ls_to_idx(ivec2(*x as i32, *y as i32));
+                if let (Some((from, priority)), Some(to)) = (routes.pending.take(), b.board[idx]) {
+                    if from != to && dropoffs.get(to).is_ok() {
+                        routes.add(from, to, priority);
+                    }
+                }
+            }
+            Action::ClearRoutes(x, y) => {
+                let idx = b.ls_to_idx(ivec2(*x as i32, *y as i32));
+                if let Some(entity) = b.board[idx] {
+                    routes.remove_all(entity);
+                }
+            }
+            Action::Upgrade(x, y) => {
+                let idx = b.ls_to_idx(ivec2(*x as i32, *y as i32));
+                if let Some(entity) = b.board[idx] {
+                    if let Ok((mut level, item, _, _)) = levels.get_mut(entity) {
+                        if level.0 < MAX_LEVEL {
+                            let cost = economy.upgrade_cost(*item, level.0);
+                            if buy(&mut player, &cost, &mut resources_for_player) {
+                                level.0 += 1;
+                            }
+                        }
+                    }
+                }
+            }
+            Action::SetRecipe(x, y, kind) => {
+                let idx = b.ls_to_idx(ivec2(*x as i32, *y as i32));
+                if let (Some(entity), Some(kind)) = (b.board[idx], R::ALL.get(*kind as usize)) {
+                    if let Ok((_, _, output, Some(mut retool))) = levels.get_mut(entity) {
+                        if economy.recipes.contains_key(kind) && output.0 != *kind {
+                            retool.next = Some(*kind);
+                        }
                     }
                 }
             }
             Action::Place(x, y, kind) => {
-                let item = Item::from_int(*kind).unwrap();
+                let Ok(item) = Item::from_int(*kind) else {
+                    continue;
+                };
                 let ls_pos = ivec2(*x as i32, *y as i32);
-                let idx = b.ls_to_idx(ls_pos);
-                if b.board[idx].is_none()
-                    && buy(&mut player, &item.cost(), &mut resources_for_player)
-                {
-                    let pos = b.ls_to_ws_vec3(b.idx_to_ls(idx));
+                let fp = Footprint::new(ls_pos, economy.footprint(item));
+                if !b.fits(&fp) {
+                    continue;
+                }
+                // Blobby ids are a u8 and 0 stands for every blobby
+                if item == Item::Blobby && player.blobby_count == u8::MAX {
+                    player.placement_refused = Some(String::from("CAN'T HAVE MORE BLOBBIES"));
+                    continue;
+                }
+                // Blobbies don't take up space on the board so they can't block anything
+                if item != Item::Blobby {
+                    if !reaches_site(&b, &fp, warehouse_tile(&sites)) {
+                        player.placement_refused = Some(String::from(
+                            "CAN'T BUILD THERE, BLOBBIES COULDN'T GET TO IT",
+                        ));
+                        continue;
+                    }
+                    let required = required_routes(&b, &blobbies, &pickups, &sites);
+                    if let Some(name) = cut_off_route(&b, &fp, &required) {
+                        player.placement_refused =
+                            Some(format!("CAN'T BUILD THERE, IT WOULD CUT OFF {}", name));
+                        continue;
+                    }
+                }
+                if buy(&mut player, &economy.cost(item), &mut resources_for_player) {
+                    player.placement_refused = None;
+                    let pos = b.ls_to_ws_vec3(ls_pos);
                     let mut ecmds = com.spawn_empty();
 
                     match item {
@@ -138,48 +306,113 @@ pub fn process_actions(
                             player.blobby_count += 1;
                             ecmds
                                 .insert(Path::default())
+                                .insert(Orders::default())
+                                .insert(BlobbyState::default())
                                 .insert(Blobby {
                                     id: player.blobby_count,
                                     dest: None,
-                                    speed: GAMESETTINGS.blobby_speed,
+                                    speed: rules.blobby_speed,
+                                    capacity: rules.blobby_capacity,
+                                    stack: rules.blobby_stack,
                                     resource_pile: None,
                                     drop_off: None,
                                     going_to_pickup: true,
+                                    auto: false,
+                                    job: None,
+                                    charge: 1.0,
+                                    charger: None,
+                                    dest_before_charge: None,
                                 })
                                 .insert(Resources::zero());
 
-                            ecmds.insert(HookedSceneBundle {
-                                scene: SceneBundle {
-                                    scene: model_assets.blobby_guy.clone(),
-                                    transform: Transform::from_translation(pos),
-                                    ..default()
-                                },
-                                hook: SceneHook::new(move |_entity, _cmds| {}),
-                            });
+                            let scene = model_assets.map(|m| m.blobby_guy.clone());
+                            insert_scene(&mut ecmds, scene, Transform::from_translation(pos));
                         }
                         Item::CopperRefinery => {
-                            spawn_factory(&mut com, &model_assets, &mut b, ls_pos, R::Copper);
+                            spawn_factory(
+                                &mut com,
+                                model_assets,
+                                &mut b,
+                                fp,
+                                item,
+                                R::Copper,
+                                &economy,
+                            );
                         }
                         Item::LithiumRefinery => {
-                            spawn_factory(&mut com, &model_assets, &mut b, ls_pos, R::Lithium);
+                            spawn_factory(
+                                &mut com,
+                                model_assets,
+                                &mut b,
+                                fp,
+                                item,
+                                R::Lithium,
+                                &economy,
+                            );
                         }
                         Item::GlassRefinery => {
-                            spawn_factory(&mut com, &model_assets, &mut b, ls_pos, R::Glass);
+                            spawn_factory(
+                                &mut com,
+                                model_assets,
+                                &mut b,
+                                fp,
+                                item,
+                                R::Glass,
+                                &economy,
+                            );
                         }
                         Item::BatteryFactory => {
-                            spawn_factory(&mut com, &model_assets, &mut b, ls_pos, R::Batteries);
+                            spawn_factory(
+                                &mut com,
+                                model_assets,
+                                &mut b,
+                                fp,
+                                item,
+                                R::Batteries,
+                                &economy,
+                            );
                         }
                         Item::LittleHatFactory => {
-                            spawn_factory(&mut com, &model_assets, &mut b, ls_pos, R::LittleHats);
+                            spawn_factory(
+                                &mut com,
+                                model_assets,
+                                &mut b,
+                                fp,
+                                item,
+                                R::LittleHats,
+                                &economy,
+                            );
                         }
                         Item::BigHatFactory => {
-                            spawn_factory(&mut com, &model_assets, &mut b, ls_pos, R::BigHats);
+                            spawn_factory(
+                                &mut com,
+                                model_assets,
+                                &mut b,
+                                fp,
+                                item,
+                                R::BigHats,
+                                &economy,
+                            );
                         }
                         Item::LightbulbFactory => {
-                            spawn_factory(&mut com, &model_assets, &mut b, ls_pos, R::Lightbulbs);
+                            spawn_factory(
+                                &mut com,
+                                model_assets,
+                                &mut b,
+                                fp,
+                                item,
+                                R::Lightbulbs,
+                                &economy,
+                            );
                         }
                         Item::OutgoingHatsFactory => {
-                            spawn_outgoing_hats(&mut com, &model_assets, &mut b, ls_pos)
+                            spawn_outgoing_hats(&mut com, model_assets, &mut b, fp)
+                        }
+                        Item::ChargingStation => {
+                            spawn_charging_station(&mut com, model_assets, &mut b, fp)
+                        }
+                        Item::Factory => {
+                            spawn_generic_factory(&mut com, model_assets, &mut b, fp, &economy)
                         }
                     };
                 }
@@ -242,6 +475,135 @@ pub fn process_actions(
     action_queue.0 = Vec::new(); // Clear action queue
 }
 
+/// Appends to the orders of blobby `id`, taking it off auto and its own pile
+fn queue_order(blobbies: &mut Query<(&Transform, &mut Blobby, &mut Orders)>, id: u8, order: Order) {
+    for (_, mut blobby, mut orders) in blobbies {
+        if id == blobby.id {
+            blobby.auto = false;
+            blobby.job = None;
+            orders.queue.push_back(order);
+        }
+    }
+}
+
+/// A walk that has to stay possible, `name` is shown to the player if a build would cut it off
+struct Route {
+    from: IVec2,
+    to: IVec2,
+    name: String,
+}
+
+/// Every building that takes or supplies resources has to be reachable from the plastic
+/// warehouse, and every blobby has to be able to reach the warehouse and its assigned pile
+/// and drop off.
+fn required_routes(
+    b: &GameBoard,
+    blobbies: &Query<(&Transform, &mut Blobby, &mut Orders)>,
+    pickups: &Query<&Footprint, With<Pickup>>,
+    sites: &Query<
+        (
+            &Footprint,
+            Option<&OutputResource>,
+            Option<&PlasticReceiver>,
+            Option<&ChargingStation>,
+        ),
+        Or<(With<Dropoff>, With<PlasticReceiver>)>,
+    >,
+) -> Vec<Route> {
+    let mut routes = Vec::new();
+    let warehouse = match warehouse_tile(sites) {
+        Some(warehouse) => warehouse,
+        None => return routes,
+    };
+    for (fp, output, plastic, station) in sites {
+        let name = match (output, plastic, station) {
+            (_, Some(_), _) => String::from("THE PLASTIC WAREHOUSE"),
+            (Some(output), None, _) => format!("THE {} FACTORY", output.0.name()),
+            (None, None, Some(_)) => String::from("THE CHARGING STATION"),
+            (None, None, None) => String::from("THE OUTGOING HATS"),
+        };
+        routes.push(Route {
+            from: warehouse,
+            to: fp.access_tile(),
+            name,
+        });
+    }
+    for (trans, blobby, _) in blobbies {
+        let from = b.ws_vec3_to_ls(trans.translation);
+        routes.push(Route {
+            from,
+            to: warehouse,
+            name: format!("BLOBBY{}", blobby.id),
+        });
+        if let Some(fp) = blobby.resource_pile.and_then(|e| pickups.get(e).ok()) {
+            routes.push(Route {
+                from,
+                to: fp.access_tile(),
+                name: format!("THE RESOURCE PILE OF BLOBBY{}", blobby.id),
+            });
+        }
+        if let Some((fp, _, _, _)) = blobby.drop_off.and_then(|e| sites.get(e).ok()) {
+            routes.push(Route {
+                from,
+                to: fp.access_tile(),
+                name: format!("THE DROP OFF OF BLOBBY{}", blobby.id),
+            });
+        }
+    }
+    routes
+}
+
+/// Where blobbies pick up plastic, the start of every route a building has to stay reachable on
+fn warehouse_tile(
+    sites: &Query<
+        (
+            &Footprint,
+            Option<&OutputResource>,
+            Option<&PlasticReceiver>,
+            Option<&ChargingStation>,
+        ),
+        Or<(With<Dropoff>, With<PlasticReceiver>)>,
+    >,
+) -> Option<IVec2> {
+    sites
+        .iter()
+        .find(|(_, _, plastic, _)| plastic.is_some())
+        .map(|(fp, _, _, _)| fp.access_tile())
+}
+
+/// Whether blobbies could walk from the warehouse to the access tile of a building at `fp`
+fn reaches_site(b: &GameBoard, fp: &Footprint, warehouse: Option<IVec2>) -> bool {
+    let access = fp.access_tile();
+    if !b.in_bounds(access) {
+        return false;
+    }
+    match warehouse {
+        Some(warehouse) => b.reachable(warehouse, Some(fp))[b.ls_to_idx(access)],
+        None => true,
+    }
+}
+
+/// The name of the first route that can be walked now but not once `fp` is built
+fn cut_off_route(b: &GameBoard, fp: &Footprint, routes: &[Route]) -> Option<String> {
+    let mut reachable: HashMap<IVec2, (Vec<bool>, Vec<bool>)> = HashMap::default();
+    for route in routes {
+        if !b.in_bounds(route.to) {
+            continue;
+        }
+        let (before, after) = reachable.entry(route.from).or_insert_with(|| {
+            (
+                b.reachable(route.from, None),
+                b.reachable(route.from, Some(fp)),
+            )
+        });
+        let idx = b.ls_to_idx(route.to);
+        if before[idx] && (!after[idx] || fp.contains(route.to)) {
+            return Some(route.name.clone());
+        }
+    }
+    None
+}
+
 fn buy(
     player: &mut PlayerState,
     cost: &Resources,
@@ -268,11 +630,20 @@ fn buy(
 #[derive(Archive, Deserialize, Serialize, Clone, Eq, PartialEq, Default, Debug)]
 #[archive(compare(PartialEq))]
 #[archive_attr(derive(CheckBytes))]
-pub struct ActionRecording(Vec<(u32, [u8; 4])>);
+pub struct ActionRecording(pub(crate) Vec<(u32, [u8; 4])>);
+
+impl ActionRecording {
+    /// The step of the last recorded action
+    pub fn last_step(&self) -> u64 {
+        self.0.last().map(|(step, _)| *step as u64).unwrap_or(0)
+    }
+}
 
 #[derive(Resource, Default)]
 pub struct GameRecorder {
     pub actions: ActionRecording,
+    // Header of a loaded replay, applied when the replay is restarted
+    pub header: Option<ReplayHeader>,
     pub disable_rec: bool,
     pub play: bool,
     pub play_head: usize,
@@ -294,6 +665,20 @@ pub enum Action {
     CheatLevel,
     MoveBlobby(u8, u8, u8),
     Place(u8, u8, u8),
+    AssignPile(u8, u8, u8),
+    UnassignPile(u8),
+    SetAuto(u8, u8),
+    QueueMove(u8, u8, u8),
+    QueuePickup(u8, u8, u8),
+    QueueDropoff(u8, u8, u8),
+    SetPatrol(u8, u8),
+    // Start of a supply route and its priority, finished by the RouteTo after it
+    RouteFrom(u8, u8, u8),
+    RouteTo(u8, u8),
+    ClearRoutes(u8, u8),
+    Upgrade(u8, u8),
+    // Switches a generic factory to the recipe for R::ALL[id]
+    SetRecipe(u8, u8, u8),
 }
 
 impl Action {
@@ -310,6 +695,18 @@ impl Action {
             Action::CheatLevel                            => [7,   0,  0,  0],
             Action::MoveBlobby(x, y, id)   => [8,  *x, *y, *id],
             Action::Place(x, y, id)        => [9,  *x, *y, *id],
+            Action::AssignPile(x, y, id)   => [10, *x, *y, *id],
+            Action::UnassignPile(id)             => [11,  0,  0, *id],
+            Action::SetAuto(on, id)        => [12, *on,  0, *id],
+            Action::QueueMove(x, y, id)    => [13, *x, *y, *id],
+            Action::QueuePickup(x, y, id)  => [14, *x, *y, *id],
+            Action::QueueDropoff(x, y, id) => [15, *x, *y, *id],
+            Action::SetPatrol(on, id)      => [16, *on,  0, *id],
+            Action::RouteFrom(x, y, p)     => [17, *x, *y, *p],
+            Action::RouteTo(x, y)               => [18, *x, *y,  0],
+            Action::ClearRoutes(x, y)           => [19, *x, *y,  0],
+            Action::Upgrade(x, y)               => [20, *x, *y,  0],
+            Action::SetRecipe(x, y, r)     => [21, *x, *y, *r],
         }
     }
 
@@ -328,7 +725,75 @@ impl Action {
             7 => Action::CheatLevel,
             8 => Action::MoveBlobby(x, y, id),
             9 => Action::Place(x, y, id),
+            10 => Action::AssignPile(x, y, id),
+            11 => Action::UnassignPile(id),
+            12 => Action::SetAuto(x, id),
+            13 => Action::QueueMove(x, y, id),
+            14 => Action::QueuePickup(x, y, id),
+            15 => Action::QueueDropoff(x, y, id),
+            16 => Action::SetPatrol(x, id),
+            17 => Action::RouteFrom(x, y, id),
+            18 => Action::RouteTo(x, y),
+            19 => Action::ClearRoutes(x, y),
+            20 => Action::Upgrade(x, y),
+            21 => Action::SetRecipe(x, y, id),
             _ => Action::Empty,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::schedule::tests::headless_app;
+
+    fn place(app: &mut App, ls: IVec2, item: Item) -> Option<String> {
+        let mut queue = app.world.resource_mut::<ActionQueue>();
+        queue.push(Action::CheatCredits);
+        queue.push(Action::Place(ls.x as u8, ls.y as u8, item as u8));
+        app.update();
+        app.world
+            .resource::<PlayerState>()
+            .placement_refused
+            .clone()
+    }
+
+    #[test]
+    fn placement_blocking_the_warehouse_is_refused() {
+        let mut app = headless_app();
+        app.update();
+        app.update();
+
+        let warehouse = app
+            .world
+            .query_filtered::<&Footprint, With<PlasticReceiver>>()
+            .single(&app.world)
+            .access_tile();
+        let refused = place(&mut app, warehouse, Item::ChargingStation);
+        assert_eq!(
+            refused.as_deref(),
+            Some("CAN'T BUILD THERE, IT WOULD CUT OFF THE PLASTIC WAREHOUSE")
+        );
+        let b = app.world.resource::<GameBoard>();
+        assert!(b.board[b.ls_to_idx(warehouse)].is_none());
+    }
+
+    #[test]
+    fn placement_without_an_access_tile_is_refused() {
+        let mut app = headless_app();
+        app.update();
+        app.update();
+
+        let b = app.world.resource::<GameBoard>();
+        let last_row = b.size[1] as i32 - 1;
+        let ls = (0..b.size[0] as i32)
+            .map(|x| ivec2(x, last_row))
+            .find(|ls| b.board[b.ls_to_idx(*ls)].is_none())
+            .unwrap();
+        let refused = place(&mut app, ls, Item::ChargingStation);
+        assert_eq!(
+            refused.as_deref(),
+            Some("CAN'T BUILD THERE, BLOBBIES COULDN'T GET TO IT")
+        );
+    }
+}
@@ -72,7 +72,7 @@ pub fn fix_material_colors(
         //https://github.com/bevyengine/bevy/pull/6828
         match event {
             AssetEvent::Created { handle } => {
-                if let Some(mut mat) = materials.get_mut(handle) {
+                if let Some(mat) = materials.get_mut(handle) {
                     let c: Vec4 = mat.base_color.into();
                     mat.base_color = Color::rgba_linear(c.x, c.y, c.z, c.w);
                     let c: Vec4 = mat.emissive.into();
@@ -1,5 +1,44 @@
-use bevy::{math::*, prelude::*};
-use pathfinding::prelude::astar;
+use std::collections::VecDeque;
+
+use bevy::{math::*, prelude::*, utils::HashMap};
+use pathfinding::prelude::{astar, bfs_reach};
+
+/// The cells covered by something on the board, `origin` is the cell with the lowest x and y
+#[derive(Component, Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Footprint {
+    pub origin: IVec2,
+    pub size: IVec2,
+}
+
+impl Footprint {
+    pub fn new(origin: IVec2, size: IVec2) -> Footprint {
+        Footprint { origin, size }
+    }
+
+    pub fn single(origin: IVec2) -> Footprint {
+        Footprint::new(origin, IVec2::ONE)
+    }
+
+    pub fn cells(&self) -> impl Iterator<Item = IVec2> {
+        let (origin, size) = (self.origin, self.size);
+        (0..size.y).flat_map(move |y| (0..size.x).map(move |x| origin + ivec2(x, y)))
+    }
+
+    pub fn contains(&self, ls: IVec2) -> bool {
+        let rel = ls - self.origin;
+        rel.x >= 0 && rel.y >= 0 && rel.x < self.size.x && rel.y < self.size.y
+    }
+
+    /// The cell blobbies walk to when picking up from or dropping off here
+    pub fn access_tile(&self) -> IVec2 {
+        self.origin + ivec2(self.size.x / 2, self.size.y)
+    }
+
+    /// How close to the center a blobby needs to be to pick up or drop off
+    pub fn reach(&self) -> f32 {
+        1.8 + (self.size.max_element() - 1) as f32 * 0.5
+    }
+}
 
 #[derive(Resource)]
 pub struct GameBoard {
@@ -9,16 +48,35 @@ pub struct GameBoard {
     pub has_blobby: Vec<bool>,
     pub start: IVec2,
     pub dest: IVec2,
+    // Bumped whenever a cell is filled or cleared, paths found before then may be stale
+    pub revision: u64,
+    // Steps to each destination from every cell, see distance_map
+    distance_maps: HashMap<IVec2, Vec<u32>>,
 }
 
+/// Smallest width or depth that still leaves room for the warehouse, depot and ore
+pub const MIN_BOARD_SIZE: usize = 8;
+/// Actions store board coordinates as u8
+pub const MAX_BOARD_SIZE: usize = 256;
+/// Distance maps kept between board changes, a map is 256 KiB on the largest board
+const MAX_DISTANCE_MAPS: usize = 64;
+
 impl Default for GameBoard {
     fn default() -> Self {
-        GameBoard::new(ivec2(-12, -12), [24, 24], ivec2(0, 0), ivec2(22, 22))
+        GameBoard::sized([24, 24])
     }
 }
 
 impl GameBoard {
     pub fn new(position: IVec2, size: [usize; 2], start: IVec2, dest: IVec2) -> GameBoard {
+        assert!(
+            size.iter()
+                .all(|s| (MIN_BOARD_SIZE..=MAX_BOARD_SIZE).contains(s)),
+            "board size {:?} must be between {} and {}",
+            size,
+            MIN_BOARD_SIZE,
+            MAX_BOARD_SIZE
+        );
         let board = vec![None; size[0] * size[1]];
         GameBoard {
             size,
@@ -27,9 +85,31 @@ impl GameBoard {
             board,
             start,
             dest,
+            revision: 0,
+            distance_maps: HashMap::default(),
         }
     }
 
+    /// A board of `size` cells centered on the world origin
+    pub fn sized(size: [usize; 2]) -> GameBoard {
+        let (w, h) = (size[0] as i32, size[1] as i32);
+        GameBoard::new(
+            ivec2(-w / 2, -h / 2),
+            size,
+            ivec2(0, 0),
+            ivec2(w - 2, h - 2),
+        )
+    }
+
+    pub fn size_vec2(&self) -> Vec2 {
+        vec2(self.size[0] as f32, self.size[1] as f32)
+    }
+
+    /// World space center of the board
+    pub fn center_ws_vec3(&self) -> Vec3 {
+        vec2_to_vec3(self.ls_to_ws_f(self.size_vec2() * 0.5))
+    }
+
     pub fn reset_has_blobby(&mut self) {
         self.has_blobby = vec![false; self.size[0] * self.size[1]];
     }
@@ -56,9 +136,9 @@ impl GameBoard {
 
     #[inline(always)]
     pub fn ls_to_idx(&self, ls: IVec2) -> usize {
-        let x = (ls.x as usize).clamp(0, self.size[0] - 1);
-        let y = (ls.y as usize).clamp(0, self.size[1] - 1);
-        x + y * self.size[1]
+        let x = ls.x.clamp(0, self.size[0] as i32 - 1) as usize;
+        let y = ls.y.clamp(0, self.size[1] as i32 - 1) as usize;
+        x + y * self.size[0]
     }
 
     #[inline(always)]
@@ -81,10 +161,88 @@ impl GameBoard {
         )
     }
 
+    /// Like `path` but around cells that hold or are reserved by a blobby, `end` excepted
+    pub fn path_around_blobbies(&self, start: IVec2, end: IVec2) -> Option<(Vec<IVec2>, u32)> {
+        astar(
+            &start,
+            |p| {
+                self.successors(*p)
+                    .filter(move |(n, _)| *n == end || !self.has_blobby[self.ls_to_idx(*n)])
+            },
+            |p| {
+                let a = (end - *p).abs();
+                (a.x + a.y) as u32
+            },
+            |p| *p == end,
+        )
+    }
+
+    /// Builds the distance map to `end` if it isn't cached yet. Maps are kept until the
+    /// board changes so any number of blobbies can head to the same place for the cost of
+    /// one search.
+    pub fn update_distance_map(&mut self, end: IVec2) {
+        if self.distance_maps.contains_key(&end) {
+            return;
+        }
+        // Blobbies heading to many different cells, start over rather than grow without end
+        if self.distance_maps.len() >= MAX_DISTANCE_MAPS {
+            self.distance_maps.clear();
+        }
+        let mut dist = vec![u32::MAX; self.board.len()];
+        let mut queue = VecDeque::new();
+        // Same as path, a filled cell can't be walked to
+        if self.in_bounds(end) && self.board[self.ls_to_idx(end)].is_none() {
+            dist[self.ls_to_idx(end)] = 0;
+            queue.push_back(end);
+        }
+        while let Some(p) = queue.pop_front() {
+            let d = dist[self.ls_to_idx(p)] + 1;
+            for (n, _) in self.successors(p) {
+                let idx = self.ls_to_idx(n);
+                if dist[idx] == u32::MAX {
+                    dist[idx] = d;
+                    queue.push_back(n);
+                }
+            }
+        }
+        self.distance_maps.insert(end, dist);
+    }
+
+    /// Steps needed to walk from `start` to `end`, None if `end` can't be reached. Builds the
+    /// distance map to `end` if needed.
+    pub fn walk_distance(&mut self, start: IVec2, end: IVec2) -> Option<u32> {
+        if !self.in_bounds(start) {
+            return None;
+        }
+        self.update_distance_map(end);
+        let dist = self.distance_maps[&end][self.ls_to_idx(start)];
+        (dist != u32::MAX).then_some(dist)
+    }
+
+    /// Follows the distance map to `end` downhill from `start`, None if the map hasn't been
+    /// built or `end` can't be reached
+    pub fn path_from_distance_map(&self, start: IVec2, end: IVec2) -> Option<(Vec<IVec2>, u32)> {
+        let dist = self.distance_maps.get(&end)?;
+        if !self.in_bounds(start) || dist[self.ls_to_idx(start)] == u32::MAX {
+            return None;
+        }
+        let cost = dist[self.ls_to_idx(start)];
+        let mut path = vec![start];
+        let mut p = start;
+        while p != end {
+            let d = dist[self.ls_to_idx(p)];
+            p = self
+                .successors(p)
+                .map(|(n, _)| n)
+                .find(|n| dist[self.ls_to_idx(*n)] == d - 1)?;
+            path.push(p);
+        }
+        Some((path, cost))
+    }
+
     #[inline(always)]
-    pub fn successors(&self, ls: IVec2) -> Vec<(IVec2, u32)> {
-        let mut s = Vec::new();
-        for offset in [
+    pub fn successors(&self, ls: IVec2) -> impl Iterator<Item = (IVec2, u32)> + '_ {
+        [
             //ivec2(0, 0),
             ivec2(-1, 0),
             ivec2(1, 0),
@@ -94,19 +252,53 @@ impl GameBoard {
             ivec2(-1, 1),
             ivec2(1, -1),
             ivec2(1, 1),
-        ] {
-            let potential_pos = ls + offset;
-            if potential_pos.clamp(IVec2::ZERO, ivec2(self.size[0] as i32, self.size[1] as i32))
-                != potential_pos
-                || self.board[self.ls_to_idx(potential_pos)].is_some()
-            {
-                continue;
-            } else {
-                s.push((potential_pos, 1));
-            }
+        ]
+        .into_iter()
+        .map(move |offset| ls + offset)
+        .filter(move |p| self.in_bounds(*p) && self.board[self.ls_to_idx(*p)].is_none())
+        .map(|p| (p, 1))
+    }
+    /// The `n` empty cells closest to `center` by walking distance, so a group of blobbies
+    /// sent to one place spreads out instead of all heading to the same cell
+    pub fn formation(&self, center: IVec2, n: usize) -> Vec<IVec2> {
+        let mut cells = bfs_reach(center, |p| self.successors(*p).map(|(p, _)| p))
+            .filter(|p| self.in_bounds(*p) && self.board[self.ls_to_idx(*p)].is_none())
+            .take(n)
+            .collect::<Vec<_>>();
+        // Not enough room, the rest share the clicked cell
+        cells.resize(n, center);
+        cells
+    }
+    /// The cell closest to `goal` that can be walked to from `start`, for when `goal` itself
+    /// can't be reached
+    pub fn closest_reachable(&self, start: IVec2, goal: IVec2) -> IVec2 {
+        bfs_reach(start, |p| self.successors(*p).map(|(p, _)| p))
+            .min_by_key(|p| {
+                let d = *p - goal;
+                d.x * d.x + d.y * d.y
+            })
+            .unwrap_or(start)
+    }
+    /// One of the `n` cells closest to `start` that can be walked to, other than `start`
+    pub fn nearby_cell(&self, start: IVec2, n: usize, pick: usize) -> Option<IVec2> {
+        let cells = bfs_reach(start, |p| self.successors(*p).map(|(p, _)| p))
+            .skip(1)
+            .take(n)
+            .collect::<Vec<_>>();
+        (!cells.is_empty()).then(|| cells[pick % cells.len()])
+    }
+    /// Marks every cell that can be walked to from `start`, as if `blocked` was also built
+    pub fn reachable(&self, start: IVec2, blocked: Option<&Footprint>) -> Vec<bool> {
+        let mut reached = vec![false; self.board.len()];
+        let cells = bfs_reach(start, |p| {
+            self.successors(*p)
+                .map(|(p, _)| p)
+                .filter(move |p| !blocked.is_some_and(|fp| fp.contains(*p)))
+        });
+        for ls in cells {
+            reached[self.ls_to_idx(ls)] = true;
         }
-
-        s
+        reached
     }
     pub fn ws_vec3_to_ls(&self, ws: Vec3) -> IVec2 {
         self.ws_to_ls(vec3_to_ivec2(ws))
@@ -120,11 +312,52 @@ impl GameBoard {
     pub fn ls_f_to_ws_vec3(&self, ls: Vec2) -> Vec3 {
         vec2_to_vec3(self.ls_to_ws_f(ls)) + vec3(0.5, 0.0, 0.5)
     }
+    pub fn footprint_ws_vec3(&self, fp: &Footprint) -> Vec3 {
+        self.ls_f_to_ws_vec3(fp.origin.as_vec2() + (fp.size - 1).as_vec2() * 0.5)
+    }
+    /// Centered on the footprint, with models scaled up to cover all of its cells
+    pub fn footprint_transform(&self, fp: &Footprint) -> Transform {
+        let scale = fp.size.as_vec2();
+        Transform::from_translation(self.footprint_ws_vec3(fp)).with_scale(vec3(
+            scale.x,
+            scale.min_element(),
+            scale.y,
+        ))
+    }
+    pub fn in_bounds(&self, ls: IVec2) -> bool {
+        ls.x >= 0 && ls.y >= 0 && ls.x < self.size[0] as i32 && ls.y < self.size[1] as i32
+    }
+    /// If every cell of the footprint is on the board and empty
+    pub fn fits(&self, fp: &Footprint) -> bool {
+        fp.cells()
+            .all(|ls| self.in_bounds(ls) && self.board[self.ls_to_idx(ls)].is_none())
+    }
+    pub fn fill(&mut self, fp: &Footprint, entity: Option<Entity>) {
+        for ls in fp.cells() {
+            let idx = self.ls_to_idx(ls);
+            self.board[idx] = entity;
+        }
+        self.changed();
+    }
+    fn changed(&mut self) {
+        self.revision += 1;
+        self.distance_maps.clear();
+    }
+    /// Despawns the entity at idx and clears every cell it covers
     pub fn destroy(&mut self, com: &mut Commands, idx: usize) {
-        if let Some(entity) = &self.board[idx] {
-            com.entity(*entity).despawn_recursive();
+        if let Some(entity) = self.board[idx] {
+            self.remove(com, entity);
+        }
+    }
+    /// Despawns `entity` and clears the cells that still hold it
+    pub fn remove(&mut self, com: &mut Commands, entity: Entity) {
+        com.entity(entity).despawn_recursive();
+        for cell in self.board.iter_mut() {
+            if *cell == Some(entity) {
+                *cell = None;
+            }
         }
-        self.board[idx] = None;
+        self.changed();
     }
 }
 
@@ -143,3 +376,87 @@ pub fn ivec2_to_vec3(p: IVec2) -> Vec3 {
 pub fn vec2_to_vec3(p: Vec2) -> Vec3 {
     vec3(p.x, 0.0, p.y)
 }
+
+#[cfg(test)]
+mod tests {
+    use bevy::ecs::system::CommandQueue;
+
+    use super::*;
+
+    // Wider than it is deep so mixing up width and depth shows
+    fn corridor() -> GameBoard {
+        GameBoard::sized([48, 12])
+    }
+
+    #[test]
+    fn idx_ls_roundtrip() {
+        let b = corridor();
+        for idx in 0..b.board.len() {
+            assert_eq!(b.ls_to_idx(b.idx_to_ls(idx)), idx);
+        }
+        assert_eq!(b.idx_to_ls(b.board.len() - 1), ivec2(47, 11));
+        assert_eq!(b.ls_to_idx(ivec2(47, 0)), 47);
+        assert_eq!(b.ls_to_idx(ivec2(0, 11)), 11 * 48);
+    }
+
+    #[test]
+    fn bounds_at_the_edges() {
+        let b = corridor();
+        assert!(b.in_bounds(ivec2(47, 11)));
+        assert!(b.in_bounds(ivec2(0, 0)));
+        assert!(!b.in_bounds(ivec2(48, 11)));
+        assert!(!b.in_bounds(ivec2(47, 12)));
+        assert!(!b.in_bounds(ivec2(11, 12)));
+        assert!(!b.in_bounds(ivec2(-1, 0)));
+        // Only the three neighbours on the board
+        assert_eq!(b.successors(ivec2(47, 11)).count(), 3);
+        assert!(b.successors(ivec2(47, 11)).all(|(p, _)| b.in_bounds(p)));
+    }
+
+    #[test]
+    fn path_reaches_the_far_corner() {
+        let mut b = corridor();
+        let end = ivec2(47, 11);
+        let (path, cost) = b.path(ivec2(0, 0), end).unwrap();
+        assert_eq!(path.last(), Some(&end));
+        // Diagonal steps cost the same as straight ones
+        assert_eq!(cost, 47);
+        assert_eq!(b.walk_distance(ivec2(0, 0), end), Some(47));
+        let (path, _) = b.path_from_distance_map(ivec2(0, 0), end).unwrap();
+        assert_eq!(path.last(), Some(&end));
+    }
+
+    #[test]
+    fn distances_follow_board_changes() {
+        let mut world = World::new();
+        let wall = world.spawn_empty().id();
+        let mut queue = CommandQueue::default();
+        let mut com = Commands::new(&mut queue, &world);
+        let mut b = corridor();
+        let (start, end) = (ivec2(0, 5), ivec2(10, 5));
+        assert_eq!(b.walk_distance(start, end), Some(10));
+
+        // A wall across the corridor with one gap at the top
+        let revision = b.revision;
+        b.fill(&Footprint::new(ivec2(5, 0), ivec2(1, 11)), Some(wall));
+        assert!(b.revision > revision);
+        assert_eq!(b.walk_distance(start, end), Some(12));
+
+        let revision = b.revision;
+        b.remove(&mut com, wall);
+        assert!(b.revision > revision);
+        assert_eq!(b.walk_distance(start, end), Some(10));
+    }
+
+    #[test]
+    fn distance_maps_are_pruned() {
+        let mut b = corridor();
+        for x in 0..48 {
+            for y in 0..2 {
+                b.walk_distance(ivec2(0, 0), ivec2(x, y));
+            }
+        }
+        assert!(b.distance_maps.len() <= MAX_DISTANCE_MAPS);
+        assert_eq!(b.walk_distance(ivec2(0, 0), ivec2(47, 11)), Some(47));
+    }
+}
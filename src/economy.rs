@@ -0,0 +1,388 @@
+use std::collections::HashMap;
+
+use bevy::{
+    asset::{AssetLoader, LoadContext, LoadedAsset},
+    prelude::*,
+    reflect::TypeUuid,
+    utils::BoxedFuture,
+};
+use serde::Deserialize;
+
+use crate::{
+    items::Item,
+    player::{PlayerState, Resources, R},
+};
+
+pub const ECONOMY_PATH: &str = "default.economy.ron";
+
+/// Used when running headless and until the asset server has loaded the economy file
+const DEFAULT_ECONOMY: &str = include_str!("../assets/default.economy.ron");
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct Recipe {
+    pub input: HashMap<R, u64>,
+    pub time: u64,
+    /// Made by one cycle, one unit of the resource the recipe is for if not given. Anything
+    /// else in here is a byproduct.
+    #[serde(default)]
+    pub output: HashMap<R, u64>,
+}
+
+fn single_tile() -> (i32, i32) {
+    (1, 1)
+}
+
+fn default_input_batches() -> u64 {
+    1
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct ItemDef {
+    pub cost: HashMap<R, u64>,
+    /// Width and depth in board cells
+    #[serde(default = "single_tile")]
+    pub footprint: (i32, i32),
+    /// Finished units a building holds before it stops making more, no limit if not given
+    #[serde(default)]
+    pub output_capacity: Option<u64>,
+    /// Recipes worth of input a building takes ahead of time
+    #[serde(default = "default_input_batches")]
+    pub input_batches: u64,
+    /// Cost of going from level 1 to 2, multiplied by the current level for higher levels.
+    /// The build cost if not given.
+    #[serde(default)]
+    pub upgrade_cost: Option<HashMap<R, u64>>,
+    /// Steps a building whose recipe can be changed waits before running the new one
+    #[serde(default)]
+    pub changeover: u64,
+}
+
+/// Build costs, recipes and process times.
+/// Loaded as an asset so balance can be tuned without recompiling, the simulation reads
+/// the copy kept in the `Economy` resource.
+#[derive(Resource, Deserialize, TypeUuid, Clone, Debug)]
+#[uuid = "6b2f0d6e-3c0a-4e55-9a3e-56e3b8c1d7f4"]
+pub struct Economy {
+    pub recipes: HashMap<R, Recipe>,
+    pub items: HashMap<Item, ItemDef>,
+    /// The file this was parsed from, stored in replays so they play back with it
+    #[serde(skip)]
+    pub source: String,
+}
+
+/// A reloaded economy waiting for the next restart, so a game is played with one economy
+/// from start to finish
+#[derive(Resource, Default)]
+pub struct PendingEconomy(pub Option<Economy>);
+
+impl Default for Economy {
+    fn default() -> Self {
+        Economy::from_ron(DEFAULT_ECONOMY.as_bytes()).expect("invalid built in economy")
+    }
+}
+
+fn to_resources(map: &HashMap<R, u64>) -> Resources {
+    Resources(map.iter().map(|(k, v)| (*k, *v)).collect())
+}
+
+impl Economy {
+    pub fn from_ron(bytes: &[u8]) -> Result<Economy, bevy::asset::Error> {
+        let mut economy = ron::de::from_bytes::<Economy>(bytes)?;
+        economy.source = String::from_utf8_lossy(bytes).into_owned();
+        let errors = economy.validate();
+        if !errors.is_empty() {
+            return Err(bevy::asset::Error::msg(errors.join(", ")));
+        }
+        Ok(economy)
+    }
+
+    /// Unknown resources and items are already rejected when parsing
+    pub fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        for (kind, recipe) in self.recipes.iter() {
+            if recipe.time == 0 {
+                errors.push(format!("recipe for {:?} has zero time", kind));
+            }
+            if recipe.input.is_empty() || recipe.input.values().any(|qty| *qty == 0) {
+                errors.push(format!("recipe for {:?} has an empty input", kind));
+            }
+            if !recipe.output.is_empty()
+                && (recipe.output.get(kind).is_none_or(|qty| *qty == 0)
+                    || recipe.output.values().any(|qty| *qty == 0))
+            {
+                errors.push(format!("recipe for {:?} must output some {:?}", kind, kind));
+            }
+        }
+        for (item, def) in self.items.iter() {
+            if def.footprint.0 < 1 || def.footprint.1 < 1 {
+                errors.push(format!("footprint for {:?} must be at least 1x1", item));
+            }
+            if def.output_capacity == Some(0) {
+                errors.push(format!("output capacity for {:?} is zero", item));
+            }
+            if def.input_batches == 0 {
+                errors.push(format!("input batches for {:?} is zero", item));
+            }
+        }
+        for item in Item::ALL {
+            if !self.items.contains_key(&item) {
+                errors.push(format!("missing build cost for {:?}", item));
+            }
+            if let Some(output) = item.output() {
+                if !self.recipes.contains_key(&output) {
+                    errors.push(format!(
+                        "missing recipe for {:?} made by {:?}",
+                        output, item
+                    ));
+                }
+            }
+        }
+        errors
+    }
+
+    /// The resources needed for one cycle of the recipe for `kind`
+    pub fn recipe(&self, kind: R) -> Resources {
+        self.recipes
+            .get(&kind)
+            .map(|recipe| to_resources(&recipe.input))
+            .unwrap_or_else(Resources::zero)
+    }
+
+    /// Everything one cycle of the recipe for `kind` makes, `kind` itself and any byproducts
+    pub fn output(&self, kind: R) -> Resources {
+        match self.recipes.get(&kind) {
+            Some(recipe) if !recipe.output.is_empty() => to_resources(&recipe.output),
+            _ => Resources([(kind, 1)].into_iter().collect()),
+        }
+    }
+
+    /// Steps needed for one cycle of the recipe for `kind`
+    pub fn time(&self, kind: R) -> u64 {
+        self.recipes
+            .get(&kind)
+            .map(|recipe| recipe.time)
+            .unwrap_or(1)
+    }
+
+    pub fn cost(&self, item: Item) -> Resources {
+        self.items
+            .get(&item)
+            .map(|def| to_resources(&def.cost))
+            .unwrap_or_else(Resources::zero)
+    }
+
+    /// Cost of raising `item` from `level` to the next level
+    pub fn upgrade_cost(&self, item: Item, level: u8) -> Resources {
+        self.items
+            .get(&item)
+            .map(|def| to_resources(def.upgrade_cost.as_ref().unwrap_or(&def.cost)))
+            .unwrap_or_else(Resources::zero)
+            .mult(level as u64)
+    }
+
+    pub fn changeover(&self, item: Item) -> u64 {
+        self.items.get(&item).map_or(0, |def| def.changeover)
+    }
+
+    /// Size in board cells, blobbies are always a single cell
+    pub fn footprint(&self, item: Item) -> IVec2 {
+        self.items
+            .get(&item)
+            .map(|def| IVec2::new(def.footprint.0, def.footprint.1))
+            .unwrap_or(IVec2::ONE)
+    }
+
+    /// Steps a building at `level` needs to make one unit of `kind`. Level 2 takes two thirds
+    /// of the recipe time and level 3 half.
+    pub fn process_time(&self, kind: R, level: u8) -> u64 {
+        (self.time(kind) * 2 / (level as u64 + 1)).max(1)
+    }
+
+    /// Finished units of each of its outputs `item` holds before it stops, more for every
+    /// level. `None` if it never stops.
+    pub fn output_capacity(&self, item: Item, level: u8) -> Option<u64> {
+        self.items
+            .get(&item)
+            .and_then(|def| def.output_capacity)
+            .map(|capacity| capacity * level as u64)
+    }
+
+    /// The most input `item` takes while making `kind`, a number of whole recipes with one
+    /// more for every level
+    pub fn input_buffer(&self, item: Item, kind: R, level: u8) -> Resources {
+        let batches = self
+            .items
+            .get(&item)
+            .map_or_else(default_input_batches, |def| def.input_batches);
+        self.recipe(kind).mult(batches + level as u64 - 1)
+    }
+
+    /// The (input, output) of one production cycle of a building
+    pub fn item_recipe(&self, item: Item) -> Option<(Resources, Resources)> {
+        item.output()
+            .map(|output| (self.recipe(output), self.output(output)))
+    }
+}
+
+#[derive(Default)]
+pub struct EconomyLoader;
+
+impl AssetLoader for EconomyLoader {
+    fn load<'a>(
+        &'a self,
+        bytes: &'a [u8],
+        load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, Result<(), bevy::asset::Error>> {
+        Box::pin(async move {
+            let economy = Economy::from_ron(bytes)?;
+            load_context.set_default_asset(LoadedAsset::new(economy));
+            Ok(())
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["economy.ron"]
+    }
+}
+
+#[derive(Resource)]
+pub struct EconomyHandle(pub Handle<Economy>);
+
+/// Loads the economy file through the asset server whenever it is (re)loaded. It is used
+/// right away before the first step and from the next restart otherwise. Files that fail
+/// validation are logged and ignored.
+pub struct EconomyPlugin;
+impl Plugin for EconomyPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_asset::<Economy>()
+            .init_asset_loader::<EconomyLoader>()
+            .add_startup_system(load_economy)
+            .add_system(apply_economy);
+    }
+}
+
+fn load_economy(mut com: Commands, asset_server: Res<AssetServer>) {
+    com.insert_resource(EconomyHandle(asset_server.load(ECONOMY_PATH)));
+}
+
+fn apply_economy(
+    mut events: EventReader<AssetEvent<Economy>>,
+    assets: Res<Assets<Economy>>,
+    player: Res<PlayerState>,
+    mut economy: ResMut<Economy>,
+    mut pending: ResMut<PendingEconomy>,
+) {
+    for event in events.iter() {
+        match event {
+            AssetEvent::Created { handle } | AssetEvent::Modified { handle } => {
+                if let Some(loaded) = assets.get(handle) {
+                    if player.step == 0 {
+                        *economy = loaded.clone();
+                        info!("Loaded economy from {}", ECONOMY_PATH);
+                    } else {
+                        pending.0 = Some(loaded.clone());
+                        info!(
+                            "Loaded economy from {}, used from the next restart",
+                            ECONOMY_PATH
+                        );
+                    }
+                }
+            }
+            AssetEvent::Removed { .. } => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn output_capacity_grows_with_level() {
+        let mut economy = Economy::default();
+        economy
+            .items
+            .get_mut(&Item::GlassRefinery)
+            .unwrap()
+            .output_capacity = Some(5);
+
+        assert_eq!(economy.output_capacity(Item::GlassRefinery, 1), Some(5));
+        assert_eq!(economy.output_capacity(Item::GlassRefinery, 3), Some(15));
+        // Uncapped unless the file sets a capacity
+        assert_eq!(economy.output_capacity(Item::CopperRefinery, 3), None);
+    }
+
+    #[test]
+    fn input_buffer_holds_a_batch_more_per_level() {
+        let mut economy = Economy::default();
+        let recipe = economy.recipe(R::Copper);
+        assert_eq!(
+            economy.input_buffer(Item::CopperRefinery, R::Copper, 1).0,
+            recipe.0
+        );
+        assert_eq!(
+            economy.input_buffer(Item::CopperRefinery, R::Copper, 3).0,
+            recipe.mult(3).0
+        );
+
+        economy
+            .items
+            .get_mut(&Item::CopperRefinery)
+            .unwrap()
+            .input_batches = 2;
+        assert_eq!(
+            economy.input_buffer(Item::CopperRefinery, R::Copper, 2).0,
+            recipe.mult(3).0
+        );
+    }
+
+    #[test]
+    fn process_time_shortens_with_level() {
+        let mut economy = Economy::default();
+        economy.recipes.get_mut(&R::Copper).unwrap().time = 120;
+        assert_eq!(economy.process_time(R::Copper, 1), 120);
+        assert_eq!(economy.process_time(R::Copper, 2), 80);
+        assert_eq!(economy.process_time(R::Copper, 3), 60);
+
+        // Never less than a step
+        economy.recipes.get_mut(&R::Copper).unwrap().time = 1;
+        assert_eq!(economy.process_time(R::Copper, 3), 1);
+    }
+
+    #[test]
+    fn output_includes_byproducts() {
+        let economy = Economy::default();
+        let glass = economy.output(R::Glass);
+        assert_eq!(glass.0.get(&R::Glass), Some(&1));
+        assert_eq!(glass.0.get(&R::Sand), Some(&1));
+        // One unit of the recipe's own resource if the file gives no output
+        let copper = economy.output(R::Copper);
+        assert_eq!(copper.0.len(), 1);
+        assert_eq!(copper.0.get(&R::Copper), Some(&1));
+    }
+
+    #[test]
+    fn generic_factory_uses_its_own_def() {
+        let mut economy = Economy::default();
+        economy.items.get_mut(&Item::Factory).unwrap().input_batches = 3;
+        economy
+            .items
+            .get_mut(&Item::Factory)
+            .unwrap()
+            .output_capacity = Some(4);
+
+        let recipe = economy.recipe(R::Batteries);
+        assert_eq!(
+            economy.input_buffer(Item::Factory, R::Batteries, 1).0,
+            recipe.mult(3).0
+        );
+        assert_eq!(
+            economy
+                .input_buffer(Item::BatteryFactory, R::Batteries, 1)
+                .0,
+            recipe.0
+        );
+        assert_eq!(economy.output_capacity(Item::Factory, 1), Some(4));
+        assert_eq!(economy.output_capacity(Item::BatteryFactory, 1), None);
+    }
+}
@@ -1,11 +1,14 @@
-use bevy::{math::*, prelude::*, utils::HashMap};
+use std::collections::VecDeque;
+
+use bevy::{ecs::system::EntityCommands, math::*, prelude::*, utils::HashMap};
 
 use bevy_scene_hook::{HookedSceneBundle, SceneHook};
 
 use crate::{
     assets::ModelAssets,
-    board::GameBoard,
-    player::{PlayerState, Resources, R},
+    board::{Footprint, GameBoard},
+    economy::Economy,
+    player::{GameSettings, PlayerState, Resources, R},
     schedule::TIMESTEP,
 };
 use int_enum::IntEnum;
@@ -13,11 +16,68 @@ use int_enum::IntEnum;
 #[derive(Component, Default)]
 pub struct Path {
     pub path: Option<(Vec<IVec2>, u32)>,
+    // Seconds spent stuck, at WANDER_AFTER_SECS the blobby steps aside and tries again
     pub new_rand_loc_timer: f32,
+    // Steps left of stepping aside
+    pub wander: u32,
+    // The destination can't be reached, the path only gets as close as possible
+    pub blocked: bool,
+    // Steps spent waiting for the next cell to free up
+    pub waiting: u32,
+    // Steps left of routing around other blobbies
+    pub detour: u32,
+    // Destination and board revision the path was found for
+    pub found_for: Option<(IVec2, u64)>,
+}
+
+impl Path {
+    /// Drops the cells already walked so the path starts at `ls` again. Returns false if
+    /// `ls` isn't at the start of the path anymore and it has to be found again.
+    fn advance_to(&mut self, ls: IVec2) -> bool {
+        match &mut self.path {
+            Some((cells, cost)) => match cells.iter().take(2).position(|cell| *cell == ls) {
+                Some(walked) => {
+                    cells.drain(..walked);
+                    *cost = cost.saturating_sub(walked as u32);
+                    true
+                }
+                None => false,
+            },
+            // Still no way there until the board changes
+            None => true,
+        }
+    }
+
+    /// Got as close as it can to a destination it can't reach, or has been held up by other
+    /// blobbies for so long it isn't going to get through
+    pub fn is_stuck(&self) -> bool {
+        let arrived = self.path.as_ref().is_none_or(|(cells, _)| cells.len() <= 1);
+        self.waiting >= STUCK_AFTER_STEPS || (self.blocked && arrived)
+    }
+
+    /// Held up by other blobbies, like when queuing at a busy pile
+    pub fn is_waiting(&self) -> bool {
+        self.waiting >= REROUTE_AFTER_STEPS
+    }
 }
 
+/// Steps a blobby waits on a blocked cell before looking for a way around
+const REROUTE_AFTER_STEPS: u32 = 30;
+/// Steps without getting any further before a blobby held up by others counts as stuck
+const STUCK_AFTER_STEPS: u32 = 600;
+/// Steps a detour is kept so blobbies don't turn straight back into the congestion
+const DETOUR_STEPS: u32 = 60;
+/// Seconds a blobby stays stuck before it steps aside to a nearby cell and tries again
+const WANDER_AFTER_SECS: f32 = 3.0;
+/// Steps spent stepping aside before heading for the destination again
+const WANDER_STEPS: u32 = 90;
+/// Blobbies go to a charging station once their charge drops below this
+const LOW_CHARGE: f32 = 0.25;
+/// How much slower blobbies with an empty battery are
+const FLAT_SPEED: f32 = 0.3;
+
 #[repr(u8)]
-#[derive(Clone, Copy, Component, PartialEq, Eq, Debug, IntEnum)]
+#[derive(Clone, Copy, Component, PartialEq, Eq, Hash, Debug, IntEnum, serde::Deserialize)]
 pub enum Item {
     Blobby = 0,
     CopperRefinery = 1,
@@ -28,24 +88,43 @@ pub enum Item {
     BigHatFactory = 6,
     LightbulbFactory = 7,
     OutgoingHatsFactory = 8,
+    ChargingStation = 9,
+    Factory = 10,
 }
 
 impl Item {
-    pub fn cost(&self) -> Resources {
+    pub const ALL: [Item; 11] = [
+        Item::Blobby,
+        Item::CopperRefinery,
+        Item::LithiumRefinery,
+        Item::GlassRefinery,
+        Item::BatteryFactory,
+        Item::LittleHatFactory,
+        Item::BigHatFactory,
+        Item::LightbulbFactory,
+        Item::OutgoingHatsFactory,
+        Item::ChargingStation,
+        Item::Factory,
+    ];
+
+    /// The resource this building produces, if any
+    pub fn output(&self) -> Option<R> {
         match self {
-            Item::Blobby => Resources(HashMap::from([(R::Plastic, 10), (R::LittleHats, 1)])),
-            Item::CopperRefinery => Resources(HashMap::from([(R::Plastic, 30)])),
-            Item::LithiumRefinery => Resources(HashMap::from([(R::Plastic, 30)])),
-            Item::GlassRefinery => Resources(HashMap::from([(R::Plastic, 30), (R::Copper, 5)])),
-            Item::BatteryFactory => Resources(HashMap::from([(R::Plastic, 50), (R::Copper, 5)])),
-            Item::LittleHatFactory => Resources(HashMap::from([(R::Plastic, 50), (R::Copper, 5)])),
-            Item::BigHatFactory => Resources(HashMap::from([(R::Plastic, 50), (R::Copper, 5)])),
-            Item::LightbulbFactory => Resources(HashMap::from([(R::Plastic, 50), (R::Copper, 5)])),
-            Item::OutgoingHatsFactory => {
-                Resources(HashMap::from([(R::Plastic, 50), (R::Copper, 5)]))
-            }
+            Item::Blobby => None,
+            Item::CopperRefinery => Some(R::Copper),
+            Item::LithiumRefinery => Some(R::Lithium),
+            Item::GlassRefinery => Some(R::Glass),
+            Item::BatteryFactory => Some(R::Batteries),
+            Item::LittleHatFactory => Some(R::LittleHats),
+            Item::BigHatFactory => Some(R::BigHats),
+            Item::LightbulbFactory => Some(R::Lightbulbs),
+            Item::OutgoingHatsFactory => None,
+            Item::ChargingStation => None,
+            // Whatever its recipe is set to
+            Item::Factory => None,
         }
     }
+
     pub fn name(&self) -> String {
         String::from(match self {
             Item::Blobby => "BLOBBY",
@@ -57,42 +136,10 @@ impl Item {
             Item::BigHatFactory => "BIG HAT FACTORY",
             Item::LightbulbFactory => "LIGHTBULB FACTORY",
             Item::OutgoingHatsFactory => "OUTGOING HATS",
+            Item::ChargingStation => "CHARGING STATION",
+            Item::Factory => "FACTORY",
         })
     }
-
-    pub fn recipe(&self) -> Option<(Resources, Resources)> {
-        match self {
-            Item::Blobby => None,
-            Item::CopperRefinery => Some((
-                R::Copper.recipe(),
-                Resources(HashMap::from([(R::Copper, 1)])),
-            )),
-            Item::LithiumRefinery => Some((
-                R::Lithium.recipe(),
-                Resources(HashMap::from([(R::Lithium, 1)])),
-            )),
-            Item::GlassRefinery => {
-                Some((R::Glass.recipe(), Resources(HashMap::from([(R::Glass, 1)]))))
-            }
-            Item::BatteryFactory => Some((
-                R::Batteries.recipe(),
-                Resources(HashMap::from([(R::Batteries, 1)])),
-            )),
-            Item::LittleHatFactory => Some((
-                R::LittleHats.recipe(),
-                Resources(HashMap::from([(R::LittleHats, 1)])),
-            )),
-            Item::BigHatFactory => Some((
-                R::BigHats.recipe(),
-                Resources(HashMap::from([(R::BigHats, 1)])),
-            )),
-            Item::LightbulbFactory => Some((
-                R::Lightbulbs.recipe(),
-                Resources(HashMap::from([(R::Lightbulbs, 1)])),
-            )),
-            Item::OutgoingHatsFactory => None,
-        }
-    }
 }
 
 #[derive(Component, Deref, DerefMut)]
@@ -102,6 +149,10 @@ pub struct Health(pub f32);
 pub struct Blobby {
     pub speed: f32,
     pub id: u8,
+    // Most units carried in total
+    pub capacity: u64,
+    // Most units of any one resource carried
+    pub stack: u64,
     pub dest: Option<IVec2>,
     //The resource pile the user has selected
     pub resource_pile: Option<Entity>,
@@ -109,22 +160,217 @@ pub struct Blobby {
     // If there is no place this should be set to None to the blobby goes back to get more resources
     pub drop_off: Option<Entity>,
     pub going_to_pickup: bool,
+    // Picks its own pile and drop off based on what factories are missing
+    pub auto: bool,
+    // The resource an auto blobby has claimed to bring to its drop off
+    pub job: Option<R>,
+    // Battery charge from 0.0 to 1.0, only drains when the blobby_drain rule is set
+    pub charge: f32,
+    // The charging station it is on the way to
+    pub charger: Option<Entity>,
+    // Where it was going before it went to charge, it carries on there once charged
+    pub dest_before_charge: Option<IVec2>,
+}
+
+impl Blobby {
+    /// Units of `r` that still fit on top of what is `carried`
+    pub fn room_for(&self, carried: &Resources, r: R) -> u64 {
+        let held = carried.0.get(&r).copied().unwrap_or(0);
+        self.capacity
+            .saturating_sub(carried.total())
+            .min(self.stack.saturating_sub(held))
+    }
+
+    /// Units of a single resource carried on a full trip
+    pub fn load(&self) -> u64 {
+        self.capacity.min(self.stack)
+    }
+
+    /// Not working a pile, not on auto and with no orders left
+    pub fn is_idle(&self, orders: &Orders) -> bool {
+        self.resource_pile.is_none() && !self.auto && orders.queue.is_empty()
+    }
+}
+
+/// What a blobby is doing, set by the systems that drive it
+#[derive(Component, Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum BlobbyState {
+    #[default]
+    Idle,
+    MovingToPile,
+    Loading,
+    MovingToDropoff,
+    Unloading,
+    Stuck,
+    // Held up by other blobbies on the way, or holding a load nothing has room for yet
+    Waiting,
+    // Walking to a cell the player picked
+    ManualMove,
+    MovingToCharger,
+    Charging,
+}
+
+impl BlobbyState {
+    pub fn name(&self) -> &'static str {
+        match self {
+            BlobbyState::Idle => "IDLE",
+            BlobbyState::MovingToPile => "GOING TO PILE",
+            BlobbyState::Loading => "LOADING",
+            BlobbyState::MovingToDropoff => "DELIVERING",
+            BlobbyState::Unloading => "UNLOADING",
+            BlobbyState::Stuck => "STUCK",
+            BlobbyState::Waiting => "WAITING",
+            BlobbyState::ManualMove => "MOVING",
+            BlobbyState::MovingToCharger => "GOING TO CHARGE",
+            BlobbyState::Charging => "CHARGING",
+        }
+    }
+}
+
+/// Sent every time a blobby changes state
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct BlobbyStateChanged {
+    pub entity: Entity,
+    pub id: u8,
+    pub from: BlobbyState,
+    pub to: BlobbyState,
+}
+
+/// Sets the state and sends an event if it changed. Walking is reported as stuck while the
+/// blobby can't get any further and as waiting while other blobbies are in the way.
+fn set_state(
+    events: &mut EventWriter<BlobbyStateChanged>,
+    entity: Entity,
+    blobby: &Blobby,
+    path: &Path,
+    state: &mut BlobbyState,
+    to: BlobbyState,
+) {
+    let walking = matches!(
+        to,
+        BlobbyState::MovingToPile
+            | BlobbyState::MovingToDropoff
+            | BlobbyState::ManualMove
+            | BlobbyState::MovingToCharger
+    );
+    let to = if walking && path.is_stuck() {
+        BlobbyState::Stuck
+    } else if walking && path.is_waiting() {
+        BlobbyState::Waiting
+    } else {
+        to
+    };
+    if *state != to {
+        events.send(BlobbyStateChanged {
+            entity,
+            id: blobby.id,
+            from: *state,
+            to,
+        });
+        *state = to;
+    }
+}
+
+/// Blobbies without a pile or orders are walking to where they were sent or idle
+pub(crate) fn update_blobby_states(
+    b: Res<GameBoard>,
+    mut events: EventWriter<BlobbyStateChanged>,
+    mut blobbies: Query<(
+        Entity,
+        &Transform,
+        &Blobby,
+        &Orders,
+        &Path,
+        &mut BlobbyState,
+    )>,
+) {
+    for (entity, trans, blobby, orders, path, mut state) in &mut blobbies {
+        if blobby.resource_pile.is_some() || !orders.queue.is_empty() || blobby.charger.is_some() {
+            continue;
+        }
+        let ls = b.ws_vec3_to_ls(trans.translation);
+        let to = match blobby.dest {
+            Some(dest) if dest != ls => BlobbyState::ManualMove,
+            _ => BlobbyState::Idle,
+        };
+        set_state(&mut events, entity, blobby, path, &mut state, to);
+    }
 }
 
 #[derive(Component)]
 pub struct PathInd;
 
+/// Paths are kept until the destination or the board changes. Destinations shared by
+/// several blobbies, like the plastic warehouse, are found with one distance map for all.
 pub(crate) fn update_blobby_paths(
-    b: Res<GameBoard>,
+    mut b: ResMut<GameBoard>,
     mut blobbies: Query<(&Transform, &mut Path, &Blobby)>,
     player: Res<PlayerState>,
 ) {
     if !player.alive() {
         return;
     }
+    let mut heading_to: HashMap<IVec2, u32> = HashMap::default();
+    for (_, _, blobby) in &blobbies {
+        if let Some(dest) = blobby.dest {
+            *heading_to.entry(dest).or_default() += 1;
+        }
+    }
+
     for (trans, mut blobby_path, blobby) in blobbies.iter_mut() {
         if let Some(dest) = blobby.dest {
-            blobby_path.path = b.path(b.ws_vec3_to_ls(trans.translation), dest);
+            let start = b.ws_vec3_to_ls(trans.translation);
+
+            if blobby_path.is_stuck() {
+                blobby_path.new_rand_loc_timer += TIMESTEP;
+            } else {
+                blobby_path.new_rand_loc_timer = 0.0;
+            }
+            if blobby_path.new_rand_loc_timer >= WANDER_AFTER_SECS {
+                blobby_path.new_rand_loc_timer = 0.0;
+                // Boxed in blobbies have nowhere to go until the board changes
+                if let Some(cell) = b.nearby_cell(start, 8, blobby.id as usize) {
+                    blobby_path.path = b.path(start, cell);
+                    blobby_path.wander = WANDER_STEPS;
+                    blobby_path.waiting = 0;
+                    blobby_path.detour = 0;
+                }
+            }
+            if blobby_path.wander > 0 {
+                blobby_path.wander -= 1;
+                blobby_path.found_for = None;
+                if blobby_path.advance_to(start) {
+                    continue;
+                }
+            }
+
+            if blobby_path.waiting >= REROUTE_AFTER_STEPS {
+                blobby_path.detour = DETOUR_STEPS;
+            }
+            if blobby_path.detour > 0 {
+                // Other blobbies move every step so detours aren't kept
+                blobby_path.detour -= 1;
+                blobby_path.found_for = None;
+                if let Some(path) = b.path_around_blobbies(start, dest) {
+                    blobby_path.path = Some(path);
+                    continue;
+                }
+            }
+
+            if blobby_path.found_for == Some((dest, b.revision)) && blobby_path.advance_to(start) {
+                continue;
+            }
+            let path = if heading_to[&dest] > 1 {
+                b.update_distance_map(dest);
+                b.path_from_distance_map(start, dest)
+                    .or_else(|| b.path(start, dest))
+            } else {
+                b.path(start, dest)
+            };
+            blobby_path.blocked = path.is_none();
+            // Get as close as possible and wait there for a way to open up
+            blobby_path.path = path.or_else(|| b.path(start, b.closest_reachable(start, dest)));
+            blobby_path.found_for = Some((dest, b.revision));
         }
     }
 }
@@ -142,7 +388,7 @@ pub(crate) fn debug_show_blobby_path(
         com.entity(entity).despawn_recursive();
     }
     if let Some((_, blobby_path)) = blobbies.iter().next() {
-        for path in &blobby_path.path {
+        if let Some(path) = &blobby_path.path {
             for p in &path.0 {
                 com.spawn(PbrBundle {
                     mesh: meshes.add(Mesh::from(shape::UVSphere {
@@ -161,60 +407,482 @@ pub(crate) fn debug_show_blobby_path(
     }
 }
 
+/// Blobbies hold the cell they are on and reserve the next cell of their path before
+/// stepping into it. A blobby whose next cell is taken waits, and after a while takes a
+/// detour in `update_blobby_paths`.
 pub(crate) fn move_blobby_along_path(
-    b: Res<GameBoard>,
-    mut blobbies: Query<(&mut Transform, &mut Path, &Blobby)>,
+    mut b: ResMut<GameBoard>,
+    rules: Res<GameSettings>,
+    mut blobbies: Query<(Entity, &mut Transform, &mut Path, &mut Blobby)>,
 ) {
-    for (mut trans, path, blobby) in blobbies.iter_mut() {
-        if let Some(path) = &path.path {
-            if path.0.len() > 1 {
-                let p = trans.translation;
-                let a = b.ls_to_ws_vec3(path.0[1]);
-                let next_pos = a;
-                //if !b.has_blobby[b.ls_to_idx(b.ws_vec3_to_ls(next_pos))] {
-                trans.translation += (next_pos - p).normalize() * TIMESTEP * blobby.speed;
-                //}
-                let prev_rot = trans.rotation;
-                let mut new_trans = *trans;
-                new_trans.look_at(next_pos, Vec3::Y);
-                trans.rotation = prev_rot.lerp(new_trans.rotation, 0.1);
+    b.reset_has_blobby();
+    for (_, trans, _, _) in &blobbies {
+        let idx = b.ls_to_idx(b.ws_vec3_to_ls(trans.translation));
+        b.has_blobby[idx] = true;
+    }
+
+    // Move in id order so the same blobby gets a contested cell on every playback
+    let mut order = blobbies
+        .iter()
+        .map(|(entity, _, _, blobby)| (blobby.id, entity))
+        .collect::<Vec<_>>();
+    order.sort();
+
+    for (_, entity) in order {
+        let (_, mut trans, mut path, mut blobby) = blobbies.get_mut(entity).unwrap();
+        let next = match &path.path {
+            Some((cells, _)) if cells.len() > 1 => cells[1],
+            _ => {
+                path.waiting = 0;
+                continue;
+            }
+        };
+        let next_idx = b.ls_to_idx(next);
+        if next != b.ws_vec3_to_ls(trans.translation) {
+            if b.has_blobby[next_idx] {
+                path.waiting += 1;
+                continue;
             }
+            b.has_blobby[next_idx] = true;
         }
+        path.waiting = 0;
+
+        let p = trans.translation;
+        let next_pos = b.ls_to_ws_vec3(next);
+        let speed = if blobby.charge > 0.0 {
+            blobby.speed
+        } else {
+            blobby.speed * FLAT_SPEED
+        };
+        trans.translation += (next_pos - p).normalize() * TIMESTEP * speed;
+        blobby.charge = (blobby.charge - TIMESTEP * speed * rules.blobby_drain).max(0.0);
+        let prev_rot = trans.rotation;
+        let mut new_trans = *trans;
+        new_trans.look_at(next_pos, Vec3::Y);
+        trans.rotation = prev_rot.lerp(new_trans.rotation, 0.1);
     }
 }
 
-pub(crate) fn blobby_get_resource(
+/// If the drop off is missing more of `r` than auto blobbies are already bringing
+fn open_demand(
+    claims: &HashMap<(Entity, R), u64>,
+    drop_off: Entity,
+    dropoff: &Dropoff,
+    r: R,
+) -> bool {
+    let need = dropoff.qty.0.get(&r).copied().unwrap_or(0);
+    let have = dropoff.input.0.get(&r).copied().unwrap_or(0);
+    need.saturating_sub(have) > claims.get(&(drop_off, r)).copied().unwrap_or(0)
+}
+
+fn has_any(res: &Resources, r: R) -> bool {
+    res.0.get(&r).is_some_and(|v| *v > 0)
+}
+
+/// Gives idle auto blobbies a pile and drop off. Every auto blobby on the way to a drop off
+/// claims a full load of what it is missing so others go and fill the rest of the shortfall.
+pub(crate) fn auto_assign_blobbies(
+    mut b: ResMut<GameBoard>,
+    routes: Res<SupplyRoutes>,
+    mut blobbies: Query<(Entity, &Transform, &mut Blobby, &Resources), Without<Pickup>>,
+    pickups: Query<(Entity, &Footprint, &Resources), (With<Pickup>, Without<Blobby>)>,
+    dropoffs: Query<(Entity, &Footprint, &Dropoff), Without<Blobby>>,
+) {
+    let mut claims: HashMap<(Entity, R), u64> = HashMap::default();
+    for (_, _, blobby, _) in &blobbies {
+        if let (true, Some(drop_off), Some(r)) = (blobby.auto, blobby.drop_off, blobby.job) {
+            *claims.entry((drop_off, r)).or_default() += blobby.load();
+        }
+    }
+
+    // Hand out jobs in id order so replays play back the same
+    let mut order = blobbies
+        .iter()
+        .filter(|(_, _, blobby, _)| blobby.auto)
+        .map(|(entity, _, blobby, _)| (blobby.id, entity))
+        .collect::<Vec<_>>();
+    order.sort();
+
+    for (_, entity) in order {
+        let (_, trans, mut blobby, carried) = blobbies.get_mut(entity).unwrap();
+        // Finish the current delivery first
+        if carried.0.values().any(|v| *v > 0) {
+            continue;
+        }
+
+        if let (Some(drop_off), Some(r)) = (blobby.drop_off, blobby.job) {
+            if let Some(claimed) = claims.get_mut(&(drop_off, r)) {
+                *claimed = claimed.saturating_sub(blobby.load());
+            }
+        }
+        // Keep the current job while it is still needed and available
+        if let (Some(pile), Some(drop_off), Some(r)) =
+            (blobby.resource_pile, blobby.drop_off, blobby.job)
+        {
+            let available = pickups.get(pile).is_ok_and(|(_, _, res)| has_any(res, r));
+            let needed = dropoffs
+                .get(drop_off)
+                .is_ok_and(|(_, _, dropoff)| open_demand(&claims, drop_off, dropoff, r));
+            let routed = routes.rank(pile, drop_off).is_some();
+            if available && needed && routed {
+                *claims.entry((drop_off, r)).or_default() += blobby.load();
+                continue;
+            }
+        }
+
+        // Otherwise take the open unit on the highest priority supply route, then with the
+        // shortest walk from here to the pile to the drop off
+        let ls = b.ws_vec3_to_ls(trans.translation);
+        let mut best = None;
+        let mut best_score = (0, u32::MAX);
+        for (drop_off, dropoff_fp, dropoff) in &dropoffs {
+            for r in R::ALL {
+                if !open_demand(&claims, drop_off, dropoff, r) {
+                    continue;
+                }
+                for (pile, pile_fp, res) in &pickups {
+                    if pile == drop_off || !has_any(res, r) {
+                        continue;
+                    }
+                    let rank = match routes.rank(pile, drop_off) {
+                        Some(rank) => rank,
+                        None => continue,
+                    };
+                    let (to_pile, to_dropoff) = match (
+                        b.walk_distance(ls, pile_fp.access_tile()),
+                        b.walk_distance(pile_fp.access_tile(), dropoff_fp.access_tile()),
+                    ) {
+                        (Some(to_pile), Some(to_dropoff)) => (to_pile, to_dropoff),
+                        _ => continue,
+                    };
+                    let dist = to_pile + to_dropoff;
+                    if best.is_none()
+                        || rank > best_score.0
+                        || (rank == best_score.0 && dist < best_score.1)
+                    {
+                        best_score = (rank, dist);
+                        best = Some((pile, drop_off, r));
+                    }
+                }
+            }
+        }
+
+        if let Some((pile, drop_off, r)) = best {
+            *claims.entry((drop_off, r)).or_default() += blobby.load();
+            blobby.resource_pile = Some(pile);
+            blobby.drop_off = Some(drop_off);
+            blobby.job = Some(r);
+        } else {
+            blobby.resource_pile = None;
+            blobby.drop_off = None;
+            blobby.job = None;
+        }
+    }
+}
+
+/// Blobbies low on charge leave what they are doing for the closest charging station with a
+/// battery to spare, where one battery fills them up again.
+pub(crate) fn charge_blobbies(
+    mut b: ResMut<GameBoard>,
+    rules: Res<GameSettings>,
+    mut events: EventWriter<BlobbyStateChanged>,
+    mut blobbies: Query<(Entity, &Transform, &mut Blobby, &Path, &mut BlobbyState)>,
+    mut stations: Query<
+        (Entity, &Transform, &Footprint, &mut Dropoff),
+        (With<ChargingStation>, Without<Blobby>),
+    >,
+) {
+    if rules.blobby_drain <= 0.0 {
+        return;
+    }
+    // Batteries already spoken for by blobbies on their way
+    let mut claims: HashMap<Entity, u64> = HashMap::default();
+    for (_, _, blobby, _, _) in &blobbies {
+        if let Some(station) = blobby.charger {
+            *claims.entry(station).or_default() += 1;
+        }
+    }
+
+    let mut order = blobbies
+        .iter()
+        .map(|(entity, _, blobby, _, _)| (blobby.id, entity))
+        .collect::<Vec<_>>();
+    order.sort();
+
+    for (_, entity) in order {
+        let (_, trans, mut blobby, path, mut state) = blobbies.get_mut(entity).unwrap();
+        if blobby.charger.is_none() && blobby.charge < LOW_CHARGE {
+            let ls = b.ws_vec3_to_ls(trans.translation);
+            let mut best = None;
+            let mut best_dist = u32::MAX;
+            for (station, _, fp, dropoff) in &stations {
+                let batteries = dropoff.input.0.get(&R::Batteries).copied().unwrap_or(0);
+                if batteries <= claims.get(&station).copied().unwrap_or(0) {
+                    continue;
+                }
+                if let Some(dist) = b.walk_distance(ls, fp.access_tile()) {
+                    if dist < best_dist {
+                        best_dist = dist;
+                        best = Some(station);
+                    }
+                }
+            }
+            if let Some(station) = best {
+                *claims.entry(station).or_default() += 1;
+                blobby.charger = Some(station);
+                blobby.dest_before_charge = blobby.dest;
+            }
+        }
+
+        let station = match blobby.charger {
+            Some(station) => station,
+            None => continue,
+        };
+        match stations.get_mut(station) {
+            Ok((_, station_trans, fp, mut dropoff)) => {
+                blobby.dest = Some(fp.access_tile());
+                let dist = trans.translation.distance(station_trans.translation);
+                if dist < fp.reach() {
+                    // Without a battery left it looks for another station next step
+                    let battery = Resources(HashMap::from([(R::Batteries, 1)]));
+                    if dropoff.input.take(&battery, &mut Resources::zero(), true) {
+                        blobby.charge = 1.0;
+                        let to = BlobbyState::Charging;
+                        set_state(&mut events, entity, &blobby, path, &mut state, to);
+                    }
+                    blobby.charger = None;
+                    blobby.dest = blobby.dest_before_charge.take();
+                } else {
+                    let to = BlobbyState::MovingToCharger;
+                    set_state(&mut events, entity, &blobby, path, &mut state, to);
+                }
+            }
+            Err(_) => {
+                blobby.charger = None;
+                blobby.dest = blobby.dest_before_charge.take();
+            }
+        }
+    }
+}
+
+/// One unit of each resource in the pile that still fits, within the total capacity. Auto
+/// blobbies only load what their job is for so byproducts are not carried around for nothing.
+fn pickup_amount(blobby: &Blobby, carried: &Resources, pile: &Resources) -> Resources {
+    let only = blobby.job.filter(|_| blobby.auto);
+    let mut room = blobby.capacity.saturating_sub(carried.total());
+    let mut amount = Resources::zero();
+    for r in R::ALL {
+        if only.is_some_and(|job| job != r) {
+            continue;
+        }
+        if room > 0 && has_any(pile, r) && blobby.room_for(carried, r) > 0 {
+            amount.0.insert(r, 1);
+            room -= 1;
+        }
+    }
+    amount
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Order {
+    Move(IVec2),
+    // Load from the pile until full or it runs out, waiting there while it is empty
+    Pickup(Entity),
+    // Hand over whatever the drop off takes, waiting there while it is full
+    Dropoff(Entity),
+}
+
+/// Orders given with shift click, carried out front to back
+#[derive(Component, Default)]
+pub struct Orders {
+    pub queue: VecDeque<Order>,
+    // Put each finished order back at the end of the queue
+    pub patrol: bool,
+}
+
+/// A player made link from a pile to a building it should feed
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct SupplyRoute {
+    pub from: Entity,
+    pub to: Entity,
+    // Routes with a higher priority are served first
+    pub priority: u8,
+}
+
+/// Supply routes in the order they were made. A pile with routes only feeds the buildings
+/// it is routed to, piles without any feed whatever needs it.
+#[derive(Resource, Default)]
+pub struct SupplyRoutes {
+    pub routes: Vec<SupplyRoute>,
+    // Set by `Action::RouteFrom` for the `Action::RouteTo` after it
+    pub pending: Option<(Entity, u8)>,
+}
+
+impl SupplyRoutes {
+    /// Adds the route, or changes its priority if it already exists
+    pub fn add(&mut self, from: Entity, to: Entity, priority: u8) {
+        match self
+            .routes
+            .iter_mut()
+            .find(|route| route.from == from && route.to == to)
+        {
+            Some(route) => route.priority = priority,
+            None => self.routes.push(SupplyRoute { from, to, priority }),
+        }
+    }
+
+    /// Removes every route from or to `entity`
+    pub fn remove_all(&mut self, entity: Entity) {
+        self.routes
+            .retain(|route| route.from != entity && route.to != entity);
+    }
+
+    /// The priority of feeding `to` from `from`, 0 if `from` has no routes and None if `from`
+    /// is routed somewhere else
+    pub fn rank(&self, from: Entity, to: Entity) -> Option<u8> {
+        let mut routed = false;
+        for route in self.routes.iter().filter(|route| route.from == from) {
+            if route.to == to {
+                return Some(route.priority);
+            }
+            routed = true;
+        }
+        (!routed).then_some(0)
+    }
+}
+
+/// Works on the first order of every blobby that has any. Blobbies with orders have no
+/// pile or drop off of their own so `blobby_get_resource` and `blobby_put_resource` leave
+/// them alone.
+pub(crate) fn follow_orders(
     b: Res<GameBoard>,
+    mut events: EventWriter<BlobbyStateChanged>,
     mut blobbies: Query<
-        (&Transform, &mut Blobby, &mut Resources),
-        (Without<Pickup>, Without<Pickup>),
+        (
+            Entity,
+            &Transform,
+            &mut Blobby,
+            &mut Resources,
+            &mut Orders,
+            &Path,
+            &mut BlobbyState,
+        ),
+        Without<Pickup>,
     >,
-    mut pickups: Query<(&Transform, &mut Resources), (With<Pickup>, Without<Blobby>)>,
+    mut pickups: Query<(&Transform, &Footprint, &mut Resources), (With<Pickup>, Without<Blobby>)>,
+    mut dropoffs: Query<(&Transform, &Footprint, &mut Dropoff), Without<Blobby>>,
 ) {
-    for (blobby_trans, mut blobby, mut blobby_resources) in &mut blobbies {
-        if let Some(blob_resource_pile) = blobby.resource_pile {
-            if let Ok((pickup_trans, mut pickup_resource)) = pickups.get_mut(blob_resource_pile) {
-                let mut blobby_missing_res = false;
-                for (k, v) in pickup_resource.0.iter() {
-                    if *v > 0 {
-                        if let Some(blobby_r) = blobby_resources.0.get(k) {
-                            if *blobby_r == 0 {
-                                blobby_missing_res = true;
-                            }
-                        } else {
-                            blobby_missing_res = true;
-                        }
+    for (entity, trans, mut blobby, mut carried, mut orders, path, mut state) in &mut blobbies {
+        let order = match orders.queue.front() {
+            Some(order) if blobby.charger.is_none() => *order,
+            _ => continue,
+        };
+        blobby.resource_pile = None;
+        blobby.drop_off = None;
+
+        // (finished, can be repeated)
+        let (done, valid) = match order {
+            Order::Move(ls) => {
+                blobby.dest = Some(ls);
+                let to = BlobbyState::ManualMove;
+                set_state(&mut events, entity, &blobby, path, &mut state, to);
+                (b.ws_vec3_to_ls(trans.translation) == ls, true)
+            }
+            Order::Pickup(pile) => match pickups.get_mut(pile) {
+                Ok((pile_trans, fp, mut pile_res)) => {
+                    blobby.dest = Some(fp.access_tile());
+                    let near = trans.translation.distance(pile_trans.translation) < fp.reach();
+                    let to = if near {
+                        BlobbyState::Loading
+                    } else {
+                        BlobbyState::MovingToPile
+                    };
+                    set_state(&mut events, entity, &blobby, path, &mut state, to);
+                    if near {
+                        let amount = pickup_amount(&blobby, &carried, &pile_res);
+                        pile_res.take(&amount, &mut carried, false);
                     }
+                    // Done once full, or at the pile with a load and nothing more to take.
+                    // Otherwise it waits at the pile for more.
+                    let more = pickup_amount(&blobby, &carried, &pile_res);
+                    let full = carried.total() >= blobby.capacity;
+                    (
+                        full || (near && more.0.is_empty() && carried.total() > 0),
+                        true,
+                    )
                 }
+                Err(_) => (true, false),
+            },
+            Order::Dropoff(drop_off) => match dropoffs.get_mut(drop_off) {
+                Ok((dropoff_trans, fp, mut dropoff)) => {
+                    blobby.dest = Some(fp.access_tile());
+                    let near = trans.translation.distance(dropoff_trans.translation) < fp.reach();
+                    let to = if near {
+                        BlobbyState::Unloading
+                    } else {
+                        BlobbyState::MovingToDropoff
+                    };
+                    set_state(&mut events, entity, &blobby, path, &mut state, to);
+                    if near && dropoff.input.needs(&dropoff.qty, &carried) {
+                        let room = dropoff.input.room(&dropoff.qty);
+                        carried.take(&room, &mut dropoff.input, false);
+                    }
+                    // Done at the drop off once it has everything it takes, while it is full
+                    // the blobby waits there for room
+                    let takes = carried
+                        .0
+                        .iter()
+                        .any(|(k, v)| *v > 0 && dropoff.qty.0.get(k).is_some_and(|q| *q > 0));
+                    (near && !takes, true)
+                }
+                Err(_) => (true, false),
+            },
+        };
+
+        if done {
+            orders.queue.pop_front();
+            if orders.patrol && valid {
+                orders.queue.push_back(order);
+            }
+        }
+    }
+}
+
+pub(crate) fn blobby_get_resource(
+    mut events: EventWriter<BlobbyStateChanged>,
+    mut blobbies: Query<
+        (
+            Entity,
+            &Transform,
+            &mut Blobby,
+            &mut Resources,
+            &Path,
+            &mut BlobbyState,
+        ),
+        (Without<Pickup>, Without<Pickup>),
+    >,
+    mut pickups: Query<(&Transform, &Footprint, &mut Resources), (With<Pickup>, Without<Blobby>)>,
+) {
+    for (entity, blobby_trans, mut blobby, mut blobby_resources, path, mut state) in &mut blobbies {
+        if let (Some(blob_resource_pile), None) = (blobby.resource_pile, blobby.charger) {
+            if let Ok((pickup_trans, pickup_fp, mut pickup_resource)) =
+                pickups.get_mut(blob_resource_pile)
+            {
+                let amount = pickup_amount(&blobby, &blobby_resources, &pickup_resource);
                 blobby.going_to_pickup = false;
-                if blobby_missing_res {
-                    if blobby_trans.translation.distance(pickup_trans.translation) < 1.8 {
-                        // Pick up ore
-                        pickup_resource.take(&Resources::one(), &mut blobby_resources, false);
-                        blobby.going_to_pickup = false;
+                if !amount.0.is_empty() {
+                    let dist = blobby_trans.translation.distance(pickup_trans.translation);
+                    if dist < pickup_fp.reach() {
+                        // Pick up ore, and keep loading until full or the pile runs out
+                        let to = BlobbyState::Loading;
+                        set_state(&mut events, entity, &blobby, path, &mut state, to);
+                        pickup_resource.take(&amount, &mut blobby_resources, false);
+                        let amount = pickup_amount(&blobby, &blobby_resources, &pickup_resource);
+                        blobby.going_to_pickup = !amount.0.is_empty();
                     } else {
-                        let ore_pos = b.ws_vec3_to_ls(pickup_trans.translation);
-                        blobby.dest = Some(ore_pos + IVec2::new(0, 1));
+                        let to = BlobbyState::MovingToPile;
+                        set_state(&mut events, entity, &blobby, path, &mut state, to);
+                        blobby.dest = Some(pickup_fp.access_tile());
                         blobby.going_to_pickup = true;
                     }
                 }
@@ -223,38 +891,109 @@ pub(crate) fn blobby_get_resource(
     }
 }
 
+pub(crate) fn deplete_ore(
+    mut com: Commands,
+    mut b: ResMut<GameBoard>,
+    mut routes: ResMut<SupplyRoutes>,
+    ores: Query<(Entity, &Resources), With<Ore>>,
+    mut blobbies: Query<&mut Blobby>,
+) {
+    for (entity, res) in &ores {
+        if res.0.values().all(|v| *v == 0) {
+            b.remove(&mut com, entity);
+            routes.remove_all(entity);
+            // Blobbies keep what they carry and finish walking to where they were going
+            for mut blobby in &mut blobbies {
+                if blobby.resource_pile == Some(entity) {
+                    blobby.resource_pile = None;
+                    blobby.drop_off = None;
+                }
+            }
+        }
+    }
+}
+
+/// Blobbies working a pile deliver to the drop off on the highest priority supply route
+/// from it, or to the closest by walking distance, that needs what they carry.
 pub(crate) fn blobby_put_resource(
-    b: Res<GameBoard>,
+    mut b: ResMut<GameBoard>,
+    routes: Res<SupplyRoutes>,
+    mut events: EventWriter<BlobbyStateChanged>,
     mut blobbies: Query<
-        (&Transform, &mut Blobby, &mut Resources),
+        (
+            Entity,
+            &Transform,
+            &mut Blobby,
+            &mut Resources,
+            &Path,
+            &mut BlobbyState,
+        ),
         (Without<Pickup>, Without<Pickup>),
     >,
-    mut dropoffs: Query<(Entity, &Transform, &mut Dropoff), Without<Blobby>>,
+    mut dropoffs: Query<(Entity, &Transform, &Footprint, &mut Dropoff), Without<Blobby>>,
 ) {
-    for (blobby_trans, mut blobby, mut blobby_resources) in &mut blobbies {
-        if blobby.resource_pile.is_some() && !blobby.going_to_pickup {
-            let mut closest = 99999.0;
-            let mut closest_dropoff = None;
-            for (dropoff_entity, dropoff_trans, dropoff) in &dropoffs {
-                let dist = blobby_trans.translation.distance(dropoff_trans.translation);
-                let needs = dropoff.input.needs(&dropoff.qty, &blobby_resources);
-                if dist < closest && needs {
-                    closest_dropoff = Some(dropoff_entity);
-                    closest = dist;
+    for (entity, blobby_trans, mut blobby, mut blobby_resources, path, mut state) in &mut blobbies {
+        let pile = match blobby.resource_pile {
+            Some(pile) if !blobby.going_to_pickup && blobby.charger.is_none() => pile,
+            _ => continue,
+        };
+        // Auto blobbies deliver to the drop off they claimed as long as it still needs it
+        let claimed = blobby.drop_off.filter(|drop_off| {
+            blobby.auto
+                && dropoffs.get(*drop_off).is_ok_and(|(_, _, _, dropoff)| {
+                    dropoff.input.needs(&dropoff.qty, &blobby_resources)
+                })
+        });
+        let target = if claimed.is_some() {
+            claimed
+        } else {
+            let ls = b.ws_vec3_to_ls(blobby_trans.translation);
+            let mut best = None;
+            let mut best_score = (0, u32::MAX);
+            for (dropoff_entity, _, dropoff_fp, dropoff) in &dropoffs {
+                if !dropoff.input.needs(&dropoff.qty, &blobby_resources) {
+                    continue;
+                }
+                let rank = match routes.rank(pile, dropoff_entity) {
+                    Some(rank) => rank,
+                    None => continue,
+                };
+                let dist = match b.walk_distance(ls, dropoff_fp.access_tile()) {
+                    Some(dist) => dist,
+                    None => continue,
+                };
+                if best.is_none()
+                    || rank > best_score.0
+                    || (rank == best_score.0 && dist < best_score.1)
+                {
+                    best_score = (rank, dist);
+                    best = Some(dropoff_entity);
                 }
             }
-            if let Some(closest_dropoff) = closest_dropoff {
-                if let Ok((_, dropoff_trans, mut dropoff)) = dropoffs.get_mut(closest_dropoff) {
-                    if closest < 1.8 {
-                        let qty = &dropoff.qty.clone();
-                        blobby_resources.take(qty, &mut dropoff.input, false);
-                    }
-                    blobby.drop_off = Some(closest_dropoff);
-                    let dropoff_pos = b.ws_vec3_to_ls(dropoff_trans.translation);
-                    blobby.dest = Some(dropoff_pos + IVec2::new(0, 1));
+            best
+        };
+        // A blobby still holding a load waits for room instead of showing as idle
+        let mut to = if blobby_resources.total() > 0 {
+            BlobbyState::Waiting
+        } else {
+            BlobbyState::Idle
+        };
+        if let Some(target) = target {
+            if let Ok((_, dropoff_trans, dropoff_fp, mut dropoff)) = dropoffs.get_mut(target) {
+                let dist = blobby_trans.translation.distance(dropoff_trans.translation);
+                if dist < dropoff_fp.reach() {
+                    // Only hand over what fits
+                    let room = dropoff.input.room(&dropoff.qty);
+                    blobby_resources.take(&room, &mut dropoff.input, false);
+                    to = BlobbyState::Unloading;
+                } else {
+                    to = BlobbyState::MovingToDropoff;
                 }
+                blobby.drop_off = Some(target);
+                blobby.dest = Some(dropoff_fp.access_tile());
             }
         }
+        set_state(&mut events, entity, &blobby, path, &mut state, to);
     }
 }
 
@@ -275,12 +1014,18 @@ pub fn receive_plastic(
 #[derive(Component)]
 pub struct Pickup;
 
+/// A finite deposit that is removed from the board once it is empty
+#[derive(Component)]
+pub struct Ore;
+
 #[derive(Component)]
 pub struct Dropoff {
     pub qty: Resources,   //The max qty that this Dropoff will take
     pub input: Resources, //the input resources this dropoff has
 }
 
+/// The recipe a factory runs, named after its main product. What one cycle makes, with any
+/// byproducts, is `Economy::output`.
 #[derive(Component)]
 pub struct OutputResource(pub R);
 
@@ -295,21 +1040,50 @@ pub struct PlasticReceiver {
     pub time: u64,
 }
 
+/// Highest level a building can be upgraded to
+pub const MAX_LEVEL: u8 = 3;
+
+/// Upgrade level of a factory, starting at 1. Higher levels work faster and hold more.
+#[derive(Component, Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Level(pub u8);
+
 #[derive(Component)]
 pub struct ProcessTimer {
     pub started: bool,
     pub time: u64,
     pub length: u64,
+    // Finished units the building holds before it stops, if it ever does
+    pub output_capacity: Option<u64>,
+    // Not starting because the output is full
+    pub blocked: bool,
+}
+
+/// Inserts the scene for a board entity, or only its transform if there is no scene
+/// because the game is running headless.
+pub fn insert_scene(ecmds: &mut EntityCommands, scene: Option<Handle<Scene>>, trans: Transform) {
+    if let Some(scene) = scene {
+        ecmds.insert(HookedSceneBundle {
+            scene: SceneBundle {
+                scene,
+                transform: trans,
+                ..default()
+            },
+            hook: SceneHook::new(move |_entity, _cmds| {}),
+        });
+    } else {
+        ecmds.insert(TransformBundle::from_transform(trans));
+    }
 }
 
 pub fn spawn_ore(
     com: &mut Commands,
-    model_assets: &ModelAssets,
+    model_assets: Option<&ModelAssets>,
     b: &mut GameBoard,
     pos: IVec2,
     kind: R,
+    rules: &GameSettings,
 ) {
-    let trans = b.ls_to_ws_vec3(pos);
+    let fp = Footprint::single(pos);
     let mut ecmds = com.spawn_empty();
     let entity = ecmds.id();
     let mut r = Resources::zero();
@@ -320,114 +1094,170 @@ pub fn spawn_ore(
             .insert(PlasticReceiver { time: 0 });
         r.0.insert(kind, 200);
     } else {
-        r.0.insert(kind, 100000000);
+        ecmds.insert(Ore);
+        r.0.insert(kind, rules.ore_amount);
     }
 
-    ecmds
-        .insert(HookedSceneBundle {
-            scene: SceneBundle {
-                scene: match kind {
-                    R::CopperOre => model_assets.copper_ore.clone(),
-                    R::LithiumOre => model_assets.lithium_ore.clone(),
-                    R::Plastic => model_assets.plastic_delivery_warehouse.clone(),
-                    R::Sand => model_assets.sand_pile.clone(),
-                    _ => model_assets.copper_ore.clone(),
-                },
-                transform: Transform::from_translation(trans),
-                ..default()
-            },
-            hook: SceneHook::new(move |_entity, _cmds| {}),
-        })
-        .insert(r)
-        .insert(Pickup);
+    let scene = model_assets.map(|model_assets| match kind {
+        R::CopperOre => model_assets.copper_ore.clone(),
+        R::LithiumOre => model_assets.lithium_ore.clone(),
+        R::Plastic => model_assets.plastic_delivery_warehouse.clone(),
+        R::Sand => model_assets.sand_pile.clone(),
+        _ => model_assets.copper_ore.clone(),
+    });
+    insert_scene(&mut ecmds, scene, b.footprint_transform(&fp));
+    ecmds.insert(r).insert(Pickup).insert(fp);
 
-    let idx = b.ls_to_idx(pos);
-    b.board[idx] = Some(entity);
+    b.fill(&fp, Some(entity));
 }
 
 pub fn spawn_factory(
     com: &mut Commands,
-    model_assets: &ModelAssets,
+    model_assets: Option<&ModelAssets>,
     b: &mut GameBoard,
-    pos: IVec2,
+    fp: Footprint,
+    item: Item,
     kind: R,
-) {
-    let trans = b.ls_to_ws_vec3(pos);
+    economy: &Economy,
+) -> Entity {
     let mut ecmds = com.spawn_empty();
     let entity = ecmds.id();
-    let qty = kind.recipe(); //.mult(2);
+    let qty = economy.input_buffer(item, kind, 1);
     let r = qty.as_zero();
+    let scene = model_assets.map(|model_assets| match kind {
+        R::Plastic => model_assets.factory.clone(),
+        R::LittleHats => model_assets.terrarium.clone(),
+        R::BigHats => model_assets.factory.clone(),
+        R::Batteries => model_assets.factory.clone(),
+        R::Copper => model_assets.copper_refinery.clone(),
+        R::CopperOre => model_assets.copper_ore.clone(),
+        R::Lithium => model_assets.lithium_refinery.clone(),
+        R::LithiumOre => model_assets.lithium_ore.clone(),
+        R::Lightbulbs => model_assets.factory.clone(),
+        R::Glass => model_assets.lithium_refinery.clone(),
+        R::Sand => model_assets.factory.clone(),
+    });
+    insert_scene(&mut ecmds, scene, b.footprint_transform(&fp));
     ecmds
-        .insert(HookedSceneBundle {
-            scene: SceneBundle {
-                scene: match kind {
-                    R::Plastic => model_assets.factory.clone(),
-                    R::LittleHats => model_assets.terrarium.clone(),
-                    R::BigHats => model_assets.factory.clone(),
-                    R::Batteries => model_assets.factory.clone(),
-                    R::Copper => model_assets.copper_refinery.clone(),
-                    R::CopperOre => model_assets.copper_ore.clone(),
-                    R::Lithium => model_assets.lithium_refinery.clone(),
-                    R::LithiumOre => model_assets.lithium_ore.clone(),
-                    R::Lightbulbs => model_assets.factory.clone(),
-                    R::Glass => model_assets.lithium_refinery.clone(),
-                    R::Sand => model_assets.factory.clone(),
-                },
-                transform: Transform::from_translation(trans),
-                ..default()
-            },
-            hook: SceneHook::new(move |_entity, _cmds| {}),
-        })
         .insert(Dropoff { qty, input: r })
+        .insert(fp)
         .insert(ResourcesAvailableToPlayer)
         .insert(ProcessTimer {
             started: false,
             time: 0,
-            length: kind.time(),
+            length: economy.process_time(kind, 1),
+            output_capacity: economy.output_capacity(item, 1),
+            blocked: false,
         })
+        .insert(item)
         .insert(OutputResource(kind))
-        .insert(Resources(HashMap::from([(kind, 0)])))
+        .insert(Level(1))
+        .insert(economy.output(kind).as_zero())
         .insert(Sellable);
 
-    match kind {
-        R::LittleHats => (),
-        _ => {
-            ecmds.insert(Pickup);
-        }
+    if picked_up(kind) {
+        ecmds.insert(Pickup);
     }
 
-    let idx = b.ls_to_idx(pos);
-    b.board[idx] = Some(entity);
+    b.fill(&fp, Some(entity));
+    entity
+}
+
+/// Little hats stay in the factory for the player to spend, anything else is collected
+pub(crate) fn picked_up(kind: R) -> bool {
+    kind != R::LittleHats
+}
+
+/// A factory whose recipe can be changed with `Action::SetRecipe`
+#[derive(Component, Default)]
+pub struct Retoolable {
+    // Set by `Action::SetRecipe`, applied by `process_factories`
+    pub next: Option<R>,
+    // Steps left before the new recipe starts, input is still taken in meanwhile
+    pub changeover: u64,
+}
+
+/// Starts out making batteries, it always uses the generic factory model
+pub fn spawn_generic_factory(
+    com: &mut Commands,
+    model_assets: Option<&ModelAssets>,
+    b: &mut GameBoard,
+    fp: Footprint,
+    economy: &Economy,
+) {
+    let entity = spawn_factory(
+        com,
+        model_assets,
+        b,
+        fp,
+        Item::Factory,
+        R::Batteries,
+        economy,
+    );
+    com.entity(entity).insert(Retoolable::default());
+}
+
+/// Upgraded buildings are drawn a little taller so their level shows on the board
+pub fn show_levels(
+    b: Res<GameBoard>,
+    mut levels: Query<(&mut Transform, &Footprint, &Level), Changed<Level>>,
+) {
+    for (mut trans, fp, level) in &mut levels {
+        *trans = b.footprint_transform(fp);
+        trans.scale.y *= 1.0 + 0.15 * (level.0 - 1) as f32;
+    }
 }
 
 #[derive(Component)]
 pub struct OutgoingHats;
 
+/// Takes batteries like any other drop off and uses them to charge blobbies
+#[derive(Component)]
+pub struct ChargingStation;
+
+/// How many batteries a charging station keeps in stock
+const STATION_BATTERIES: u64 = 4;
+
+pub fn spawn_charging_station(
+    com: &mut Commands,
+    model_assets: Option<&ModelAssets>,
+    b: &mut GameBoard,
+    fp: Footprint,
+) {
+    let mut ecmds = com.spawn_empty();
+    let entity = ecmds.id();
+    let qty = Resources(HashMap::from([(R::Batteries, STATION_BATTERIES)]));
+    let r = qty.as_zero();
+    let scene = model_assets.map(|model_assets| model_assets.factory.clone());
+    insert_scene(&mut ecmds, scene, b.footprint_transform(&fp));
+    ecmds
+        .insert(Dropoff { qty, input: r })
+        .insert(fp)
+        .insert(ChargingStation)
+        .insert(ResourcesAvailableToPlayer)
+        .insert(Resources::zero())
+        .insert(Sellable);
+    b.fill(&fp, Some(entity));
+}
+
 #[derive(Component)]
 pub struct Sellable;
 
 pub fn spawn_outgoing_hats(
     com: &mut Commands,
-    model_assets: &ModelAssets,
+    model_assets: Option<&ModelAssets>,
     b: &mut GameBoard,
-    pos: IVec2,
+    fp: Footprint,
 ) {
-    let trans = b.ls_to_ws_vec3(pos);
     let mut ecmds = com.spawn_empty();
     let entity = ecmds.id();
     let qty = Resources(HashMap::from([(R::BigHats, 99999999999)]));
     let r = qty.as_zero();
+    let scene = model_assets.map(|model_assets| model_assets.outgoing_hats.clone());
+    insert_scene(&mut ecmds, scene, b.footprint_transform(&fp));
     ecmds
-        .insert(HookedSceneBundle {
-            scene: SceneBundle {
-                scene: model_assets.outgoing_hats.clone(),
-                transform: Transform::from_translation(trans),
-                ..default()
-            },
-            hook: SceneHook::new(move |_entity, _cmds| {}),
-        })
         .insert(Dropoff { qty, input: r })
+        .insert(fp)
         .insert(OutgoingHats);
-    let idx = b.ls_to_idx(pos);
-    b.board[idx] = Some(entity);
+    b.fill(&fp, Some(entity));
 }
@@ -2,6 +2,7 @@
 
 use std::f32::consts::TAU;
 
+use action::GameRecorder;
 use assets::{fix_material_colors, AudioAssets, FontAssets, ModelAssets};
 use audio::GameAudioPlugin;
 use bevy::{
@@ -12,28 +13,32 @@ use bevy::{
     window::{PresentMode, WindowMode, WindowResizeConstraints},
 };
 use bevy_asset_loader::prelude::{LoadingState, LoadingStateAppExt};
+use economy::{Economy, EconomyPlugin, PendingEconomy};
 
 use bevy_mod_raycast::{RaycastMesh, RaycastSource};
 
 use bevy_scene_hook::HookPlugin;
-use board::GameBoard;
+use board::{Footprint, GameBoard};
 
 use items::{
     spawn_ore, spawn_outgoing_hats, Blobby, Dropoff, InitialPlayerResources,
-    ResourcesAvailableToPlayer,
+    ResourcesAvailableToPlayer, SupplyRoutes,
 };
 use iyes_loopless::prelude::*;
-use player::{MyRaycastSet, PlayerState, Resources, R};
+use player::{GameSettings, MyRaycastSet, PlayerState, Resources, R};
 
 use rand::{seq::SliceRandom, Rng};
 use rand_pcg::Pcg32;
+use schedule::HarvestSimPlugin;
 use ui::GameUI;
 pub mod action;
 pub mod assets;
 pub mod audio;
 pub mod board;
+pub mod economy;
 pub mod items;
 pub mod player;
+pub mod replay;
 pub mod schedule;
 pub mod ui;
 
@@ -85,12 +90,14 @@ pub fn main() {
                 }),
         )
         //.add_plugin(HotReloadPlugin::default())
-        .insert_resource(GameBoard::default())
-        .insert_resource(RestartGame::default())
-        .insert_resource(GameRng::default())
-        .add_plugin(HookPlugin);
-
-    app.add_plugin(GameUI).add_plugin(GameAudioPlugin);
+        .add_plugin(HookPlugin)
+        .add_plugin(HarvestSimPlugin::default())
+        .insert_resource(MapSeed::from_args())
+        .insert_resource(GameSettings::from_args());
+
+    app.add_plugin(GameUI)
+        .add_plugin(GameAudioPlugin)
+        .add_plugin(EconomyPlugin);
     schedule::setup_schedule(&mut app);
 
     #[cfg(target_arch = "wasm32")]
@@ -102,19 +109,54 @@ pub fn main() {
         .add_system_set(
             ConditionSet::new()
                 .run_in_state(GameState::RunLevel)
-                //.with_system()
+                .with_system(frame_board)
+                .with_system(items::show_levels)
                 .into(),
         );
 
     app.run();
 }
 
+pub const DEFAULT_MAP_SEED: u64 = 0xcafef00dd15ea5e5;
+
 #[derive(Resource, Deref, DerefMut)]
 pub struct GameRng(pub Pcg32);
 
+impl GameRng {
+    pub fn new(seed: u64) -> Self {
+        GameRng(Pcg32::new(seed, 0xa02bdbf7bb3c0a7))
+    }
+}
+
 impl Default for GameRng {
     fn default() -> Self {
-        GameRng(Pcg32::new(0xcafef00dd15ea5e5, 0xa02bdbf7bb3c0a7))
+        GameRng::new(DEFAULT_MAP_SEED)
+    }
+}
+
+/// Seed used to generate the map when a game is started or restarted
+#[derive(Resource, Deref, DerefMut, Clone, Copy, Debug)]
+pub struct MapSeed(pub u64);
+
+impl Default for MapSeed {
+    fn default() -> Self {
+        MapSeed(DEFAULT_MAP_SEED)
+    }
+}
+
+impl MapSeed {
+    /// Reads `--seed <u64>` from the command line, otherwise uses the default seed
+    pub fn from_args() -> Self {
+        let args: Vec<String> = std::env::args().collect();
+        args.windows(2)
+            .find(|w| w[0] == "--seed")
+            .and_then(|w| w[1].parse().ok())
+            .map(MapSeed)
+            .unwrap_or_default()
+    }
+
+    pub fn random() -> Self {
+        MapSeed(rand::random())
     }
 }
 
@@ -126,13 +168,11 @@ fn setup_level(
     mut com: Commands,
     mut meshes: ResMut<Assets<Mesh>>,
     mut materials: ResMut<Assets<StandardMaterial>>,
-    model_assets: Res<ModelAssets>,
-    mut b: ResMut<GameBoard>,
-    mut rng: ResMut<GameRng>,
 ) {
     // plane
     com.spawn(PbrBundle {
-        mesh: meshes.add(Mesh::from(shape::Plane { size: 24.0 })),
+        // Scaled to the board size by frame_board
+        mesh: meshes.add(Mesh::from(shape::Plane { size: 1.0 })),
         material: materials.add(StandardMaterial {
             base_color: Color::rgba(0.2, 0.2, 0.2, 0.0),
             perceptual_roughness: 0.4,
@@ -169,41 +209,117 @@ fn setup_level(
         ..default()
     })
     .insert(RaycastSource::<MyRaycastSet>::new());
+}
+
+/// Fits the floor plane and camera to the board whenever its size changes. While a blobby
+/// is in focus the camera follows it instead.
+fn frame_board(
+    b: Res<GameBoard>,
+    player: Res<PlayerState>,
+    mut framed: Local<Option<([usize; 2], Option<Entity>)>>,
+    spawned: Query<(), Added<Board>>,
+    blobbies: Query<&Transform, (With<Blobby>, Without<Board>, Without<Camera3d>)>,
+    mut planes: Query<&mut Transform, (With<Board>, Without<Camera3d>)>,
+    mut cameras: Query<&mut Transform, (With<Camera3d>, Without<Board>)>,
+) {
+    let focus = player.focus.filter(|entity| blobbies.contains(*entity));
+    if *framed == Some((b.size, focus)) && spawned.is_empty() && focus.is_none() {
+        return;
+    }
+    *framed = Some((b.size, focus));
 
-    init_game(&mut com, &model_assets, &mut b, &mut rng);
+    let center = b.center_ws_vec3();
+    let size = b.size_vec2();
+    for mut trans in &mut planes {
+        trans.translation = center;
+        trans.scale = vec3(size.x, 1.0, size.y);
+    }
+
+    // Keep the view direction of the original 24x24 board and back off to fit the longer side,
+    // or show a 24x24 area around the blobby in focus
+    let (target, zoom) = match focus.and_then(|entity| blobbies.get(entity).ok()) {
+        Some(trans) => (trans.translation * vec3(1.0, 0.0, 1.0), 1.0),
+        None => (center, size.max_element() / 24.0),
+    };
+    let side = 3.0;
+    for mut trans in &mut cameras {
+        *trans = Transform::from_translation(target + vec3(48.0 + side, 48.0, 48.0 - side) * zoom)
+            .looking_at(target + vec3(side, -2.0, -side) * zoom, Vec3::Y);
+    }
 }
 
+pub(crate) fn setup_game(
+    mut com: Commands,
+    model_assets: Option<Res<ModelAssets>>,
+    mut b: ResMut<GameBoard>,
+    mut rng: ResMut<GameRng>,
+    seed: Res<MapSeed>,
+    rules: Res<GameSettings>,
+) {
+    *b = GameBoard::sized(rules.board_size());
+    init_game(
+        &mut com,
+        model_assets.as_deref(),
+        &mut b,
+        &mut rng,
+        *seed,
+        &rules,
+    );
+}
+
+/// Spawns the initial game entities. `model_assets` is `None` when running headless,
+/// in which case entities are spawned with transforms only.
 pub fn init_game(
     com: &mut Commands,
-    model_assets: &ModelAssets,
+    model_assets: Option<&ModelAssets>,
     b: &mut GameBoard,
     rng: &mut GameRng,
+    seed: MapSeed,
+    rules: &GameSettings,
 ) {
+    *rng = GameRng::new(*seed);
+
     // Player initial resources
     com.spawn(ResourcesAvailableToPlayer)
         .insert(InitialPlayerResources)
         .insert(Resources::player_default());
 
-    for _ in 0..20 {
-        let x = rng.0.gen_range(3..20);
-        let y = rng.0.gen_range(3..20);
+    // 20 deposits on the original 24x24 board, keeping the same density on other sizes
+    let (w, h) = (b.size[0] as i32, b.size[1] as i32);
+    let deposits = (20 * w * h / (24 * 24)).max(1);
+    for _ in 0..deposits {
+        let x = rng.0.gen_range(3..w - 4);
+        let y = rng.0.gen_range(3..h - 4);
         let kind = [R::CopperOre, R::LithiumOre, R::Sand]
             .choose(&mut rng.0)
             .unwrap();
+        // Two deposits rolled on the same cell, keep the first
+        if !b.fits(&Footprint::single(IVec2::new(x, y))) {
+            continue;
+        }
 
-        spawn_ore(com, model_assets, b, IVec2::new(x, y), *kind);
+        spawn_ore(com, model_assets, b, IVec2::new(x, y), *kind, rules);
     }
 
     // Plastic
-    spawn_ore(com, model_assets, b, IVec2::new(2, 2), R::Plastic);
-
-    spawn_outgoing_hats(com, model_assets, b, IVec2::new(22, 22));
-
-    com.spawn(SceneBundle {
-        scene: model_assets.board.clone(),
-        transform: Transform::from_translation(vec3(0.0, -0.1, 0.0)),
-        ..default()
-    });
+    spawn_ore(com, model_assets, b, IVec2::new(2, 2), R::Plastic, rules);
+
+    spawn_outgoing_hats(
+        com,
+        model_assets,
+        b,
+        Footprint::single(IVec2::new(w - 2, h - 2)),
+    );
+
+    if let Some(model_assets) = model_assets {
+        // The board model is made for 24x24
+        com.spawn(SceneBundle {
+            scene: model_assets.board.clone(),
+            transform: Transform::from_translation(b.center_ws_vec3() + vec3(0.0, -0.1, 0.0))
+                .with_scale(vec3(w as f32 / 24.0, 1.0, h as f32 / 24.0)),
+            ..default()
+        });
+    }
 }
 
 #[derive(Resource, Deref, DerefMut, Default)]
@@ -220,10 +336,27 @@ fn restart_game(
     dropoff: Query<Entity, With<Dropoff>>,
     scenes: Query<Entity, With<Handle<Scene>>>,
     mut rng: ResMut<GameRng>,
-    model_assets: Res<ModelAssets>,
+    model_assets: Option<Res<ModelAssets>>,
+    game_recorder: Res<GameRecorder>,
+    mut rules: ResMut<GameSettings>,
+    mut seed: ResMut<MapSeed>,
+    mut routes: ResMut<SupplyRoutes>,
+    (mut economy, mut pending_economy): (ResMut<Economy>, ResMut<PendingEconomy>),
 ) {
     if **restart_game {
         **restart_game = false;
+        if game_recorder.play {
+            if let Some(header) = &game_recorder.header {
+                *rules = header.rules.clone();
+                *seed = MapSeed(header.seed);
+                match header.economy() {
+                    Ok(replay_economy) => *economy = replay_economy,
+                    Err(e) => warn!("Replay economy is invalid, keeping the current one: {e}"),
+                }
+            }
+        } else if let Some(next) = pending_economy.0.take() {
+            *economy = next;
+        }
         for e in blobbies.iter() {
             com.entity(e).despawn_recursive();
         }
@@ -236,12 +369,20 @@ fn restart_game(
         for e in scenes.iter() {
             com.entity(e).despawn_recursive();
         }
-        *b = GameBoard::default();
+        *b = GameBoard::sized(rules.board_size());
+        *routes = SupplyRoutes::default();
 
         let old_time_multiplier = player.time_multiplier;
         *player = PlayerState::default();
         player.time_multiplier = old_time_multiplier;
-        init_game(&mut com, &model_assets, &mut b, &mut rng);
+        init_game(
+            &mut com,
+            model_assets.as_deref(),
+            &mut b,
+            &mut rng,
+            *seed,
+            &rules,
+        );
     }
 }
 
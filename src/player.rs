@@ -4,24 +4,75 @@ use bevy_egui::{
     EguiContext,
 };
 use bevy_mod_raycast::{Intersection, RaycastMethod, RaycastSource};
+use bytecheck::CheckBytes;
+use rkyv::{Archive, Deserialize, Serialize};
 
 use crate::{
     action::{Action, ActionQueue},
     assets::ModelAssets,
-    board::GameBoard,
+    board::{Footprint, GameBoard, MAX_BOARD_SIZE, MIN_BOARD_SIZE},
+    economy::Economy,
     items::{
-        Blobby, Dropoff, Item, OutgoingHats, OutputResource, Pickup, ProcessTimer,
-        ResourcesAvailableToPlayer,
+        picked_up, Blobby, BlobbyState, Dropoff, Item, Level, Ore, OutgoingHats, OutputResource,
+        Pickup, ProcessTimer, ResourcesAvailableToPlayer, Retoolable, MAX_LEVEL,
     },
     schedule::TIMESTEP,
-    ui::TEXT_COLOR2,
+    ui::{TEXT_COLOR2, WARNING_COLOR},
 };
 
+/// Rules the simulation depends on, stored in replays so they play back the same
+#[derive(Resource, Archive, Deserialize, Serialize, Clone, PartialEq, Debug)]
+#[archive_attr(derive(CheckBytes))]
 pub struct GameSettings {
     pub blobby_speed: f32,
+    // Most units a new blobby can carry in total
+    pub blobby_capacity: u64,
+    // Most units of any one resource a new blobby can carry
+    pub blobby_stack: u64,
+    // Units in each copper, lithium and sand deposit when the map is generated
+    pub ore_amount: u64,
+    // Width and depth of the board in cells
+    pub board_size: [u32; 2],
+    // Charge a blobby uses per cell walked, a full battery is 1.0. Zero turns batteries off.
+    pub blobby_drain: f32,
 }
 
-#[derive(Copy, Clone, Hash, PartialEq, Eq, Debug)]
+impl GameSettings {
+    /// The default rules with the board size taken from `--board WIDTHxDEPTH` and the units in
+    /// each deposit from `--ore N` if they were passed, and blobbies running on batteries with
+    /// `--batteries`
+    pub fn from_args() -> Self {
+        let mut rules = GAMESETTINGS;
+        let args = std::env::args().collect::<Vec<_>>();
+        let size = args
+            .windows(2)
+            .find(|w| w[0] == "--board")
+            .and_then(|w| w[1].split_once('x'))
+            .and_then(|(w, h)| Some([w.parse().ok()?, h.parse().ok()?]));
+        if let Some(size) = size {
+            rules.board_size = size;
+        }
+        let ore_amount = args
+            .windows(2)
+            .find(|w| w[0] == "--ore")
+            .and_then(|w| w[1].parse().ok());
+        if let Some(ore_amount) = ore_amount {
+            rules.ore_amount = ore_amount;
+        }
+        if args.iter().any(|arg| arg == "--batteries") {
+            rules.blobby_drain = BATTERY_DRAIN;
+        }
+        rules
+    }
+
+    pub fn board_size(&self) -> [usize; 2] {
+        let [w, h] = self.board_size;
+        let clamp = |s: u32| (s as usize).clamp(MIN_BOARD_SIZE, MAX_BOARD_SIZE);
+        [clamp(w), clamp(h)]
+    }
+}
+
+#[derive(Copy, Clone, Hash, PartialEq, Eq, Debug, serde::Deserialize)]
 pub enum R {
     Plastic,
     LittleHats,
@@ -40,6 +91,20 @@ pub enum R {
 pub struct Resources(pub HashMap<R, u64>);
 
 impl R {
+    pub const ALL: [R; 11] = [
+        R::Plastic,
+        R::LittleHats,
+        R::BigHats,
+        R::Batteries,
+        R::Copper,
+        R::CopperOre,
+        R::Lithium,
+        R::LithiumOre,
+        R::Lightbulbs,
+        R::Glass,
+        R::Sand,
+    ];
+
     pub fn name(&self) -> String {
         String::from(match self {
             R::Plastic => "PLASTIC",
@@ -55,40 +120,6 @@ impl R {
             R::Sand => "SAND",
         })
     }
-    pub fn recipe(&self) -> Resources {
-        match self {
-            R::Plastic => Resources(HashMap::from([(R::Plastic, 1)])),
-            R::LittleHats => Resources(HashMap::from([(R::Plastic, 10)])),
-            R::BigHats => Resources(HashMap::from([
-                (R::Plastic, 10),
-                (R::Batteries, 1),
-                (R::Lightbulbs, 1),
-            ])),
-            R::Batteries => Resources(HashMap::from([(R::Copper, 2), (R::Lithium, 2)])),
-            R::Copper => Resources(HashMap::from([(R::CopperOre, 2)])),
-            R::CopperOre => Resources(HashMap::from([(R::CopperOre, 1)])),
-            R::Lithium => Resources(HashMap::from([(R::LithiumOre, 2)])),
-            R::LithiumOre => Resources(HashMap::from([(R::LithiumOre, 1)])),
-            R::Lightbulbs => Resources(HashMap::from([(R::Copper, 2), (R::Glass, 2)])),
-            R::Glass => Resources(HashMap::from([(R::Sand, 3)])),
-            R::Sand => Resources(HashMap::from([(R::Sand, 3)])),
-        }
-    }
-    pub fn time(&self) -> u64 {
-        match self {
-            R::Plastic => 100,
-            R::LittleHats => 300,
-            R::BigHats => 200,
-            R::Batteries => 150,
-            R::Copper => 100,
-            R::CopperOre => 100,
-            R::Lithium => 100,
-            R::LithiumOre => 100,
-            R::Lightbulbs => 150,
-            R::Glass => 100,
-            R::Sand => 100,
-        }
-    }
 }
 
 fn draw_row(
@@ -105,7 +136,7 @@ fn draw_row(
     let show = show_if_zero || val > 0;
     if show_if_zero || val > 0 {
         ui.label(name);
-        ui.label(&format!("{}", val));
+        ui.label(format!("{}", val));
         ui.end_row();
     }
     show
@@ -255,6 +286,30 @@ impl Resources {
         ret
     }
 
+    /// How many times all of `unit` fits in these resources
+    pub fn count_of(&self, unit: &Resources) -> u64 {
+        unit.0
+            .iter()
+            .filter(|(_, v)| **v > 0)
+            .map(|(k, v)| self.0.get(k).copied().unwrap_or(0) / *v)
+            .min()
+            .unwrap_or(0)
+    }
+
+    pub fn total(&self) -> u64 {
+        self.0.values().sum()
+    }
+
+    /// How much more of each resource fits before reaching `max`
+    pub fn room(&self, max: &Resources) -> Resources {
+        let mut ret = Resources::zero();
+        for (key, max_v) in max.0.iter() {
+            let v = self.0.get(key).copied().unwrap_or(0);
+            ret.0.insert(*key, max_v.saturating_sub(v));
+        }
+        ret
+    }
+
     pub fn as_zero(&self) -> Resources {
         let mut new = self.clone();
         for (_, v) in new.0.iter_mut() {
@@ -285,98 +340,23 @@ impl Resources {
         show_if_none: bool,
         draw_separators: bool,
     ) {
+        // Raw materials, refined materials then finished goods
+        let groups: [&[R]; 3] = [
+            &[R::Plastic, R::Sand, R::CopperOre, R::LithiumOre],
+            &[R::Copper, R::Lithium, R::Glass],
+            &[R::Batteries, R::Lightbulbs, R::LittleHats, R::BigHats],
+        ];
         egui::Grid::new(format!("resources grid {id}")).show(ui, |ui| {
-            let a = draw_row(
-                ui,
-                " PLASTIC",
-                self.0.get(&R::Plastic),
-                show_if_zero,
-                show_if_none,
-            );
-            let b = draw_row(
-                ui,
-                " SAND",
-                self.0.get(&R::Sand),
-                show_if_zero,
-                show_if_none,
-            );
-            let c = draw_row(
-                ui,
-                " COPPER ORE",
-                self.0.get(&R::CopperOre),
-                show_if_zero,
-                show_if_none,
-            );
-            let d = draw_row(
-                ui,
-                " LITHIUM ORE",
-                self.0.get(&R::LithiumOre),
-                show_if_zero,
-                show_if_none,
-            );
-
-            if (a || b || c || d) && draw_separators {
-                //ui.separator();
-                //ui.separator();
-                ui.end_row();
-            }
-
-            let a = draw_row(
-                ui,
-                " COPPER",
-                self.0.get(&R::Copper),
-                show_if_zero,
-                show_if_none,
-            );
-            let b = draw_row(
-                ui,
-                " LITHIUM",
-                self.0.get(&R::Lithium),
-                show_if_zero,
-                show_if_none,
-            );
-            let c = draw_row(
-                ui,
-                " GLASS",
-                self.0.get(&R::Glass),
-                show_if_zero,
-                show_if_none,
-            );
-
-            if (a || b || c) && draw_separators {
-                //ui.separator();
-                //ui.separator();
-                ui.end_row();
+            for (i, group) in groups.iter().enumerate() {
+                let mut any = false;
+                for r in group.iter() {
+                    let name = format!(" {}", r.name());
+                    any |= draw_row(ui, &name, self.0.get(r), show_if_zero, show_if_none);
+                }
+                if any && draw_separators && i + 1 < groups.len() {
+                    ui.end_row();
+                }
             }
-
-            draw_row(
-                ui,
-                " BATTERIES",
-                self.0.get(&R::Batteries),
-                show_if_zero,
-                show_if_none,
-            );
-            draw_row(
-                ui,
-                " LIGHT BULBS",
-                self.0.get(&R::Lightbulbs),
-                show_if_zero,
-                show_if_none,
-            );
-            draw_row(
-                ui,
-                " LITTLE HATS",
-                self.0.get(&R::LittleHats),
-                show_if_zero,
-                show_if_none,
-            );
-            draw_row(
-                ui,
-                " BIG HATS",
-                self.0.get(&R::BigHats),
-                show_if_zero,
-                show_if_none,
-            );
         });
     }
 }
@@ -386,18 +366,44 @@ pub struct PlayerState {
     pub combined_resources: Resources,
     pub item_to_place: Option<Item>,
     pub sell_mode: bool,
+    // Clicking a building upgrades it
+    pub upgrade_mode: bool,
+    // Clicking a generic factory switches it to this recipe
+    pub recipe_mode: Option<R>,
     pub level_time: f32,
     pub level: f32,
     pub time_multiplier: f64,
     pub step: u64,
     pub blobby_count: u8,
-    pub selected_entity: Option<Entity>,
+    // Selected blobbies
+    pub selected: Vec<Entity>,
+    // Selections saved with ctrl + number
+    pub control_groups: [Vec<Entity>; 10],
+    // Clicks make supply routes instead of selecting
+    pub route_mode: bool,
+    // Cell of the pile the next supply route starts at
+    pub route_start: Option<IVec2>,
+    pub route_priority: u8,
+    // Blobby the camera follows
+    pub focus: Option<Entity>,
     pub delivery_dealine: f64,
     pub required_hats: u64,
     pub alive_set: bool,
+    /// Why the last placement was refused, cleared by the next successful one
+    pub placement_refused: Option<String>,
 }
 
-pub const GAMESETTINGS: GameSettings = GameSettings { blobby_speed: 4.0 };
+pub const GAMESETTINGS: GameSettings = GameSettings {
+    blobby_speed: 4.0,
+    blobby_capacity: 1,
+    blobby_stack: 1,
+    ore_amount: 400,
+    board_size: [24, 24],
+    blobby_drain: 0.0,
+};
+
+/// Charge used per cell walked with `--batteries`, a battery lasts 250 cells
+pub const BATTERY_DRAIN: f32 = 0.004;
 
 impl PlayerState {
     pub fn enemy_speed_boost(&self) -> f32 {
@@ -427,15 +433,23 @@ impl Default for PlayerState {
             combined_resources: Resources::zero_all_keys(),
             item_to_place: None,
             sell_mode: false,
+            upgrade_mode: false,
+            recipe_mode: None,
             level_time: 0.0,
             level: 0.0,
             time_multiplier: 1.0,
             step: 0,
             blobby_count: 0,
-            selected_entity: None,
+            selected: Vec::new(),
+            control_groups: Default::default(),
+            route_mode: false,
+            route_start: None,
+            route_priority: 1,
+            focus: None,
             delivery_dealine: 50000.0,
             required_hats: 1,
             alive_set: true,
+            placement_refused: None,
         }
     }
 }
@@ -488,33 +502,30 @@ pub fn mouse_interact(
     intersections: Query<&Intersection<MyRaycastSet>>,
     b: Res<GameBoard>,
     buttons: Res<Input<MouseButton>>,
+    keys: Res<Input<KeyCode>>,
     mut game_cursor: Query<(&mut Transform, &mut Handle<Mesh>), With<GameCursor>>,
-    mut selected_cursor: Query<&mut Transform, (With<SelectedCursor>, Without<GameCursor>)>,
+    mut drag_start: Local<Option<Vec2>>,
     mut player: ResMut<PlayerState>,
     mut action_queue: ResMut<ActionQueue>,
-    // TODO use action system for replays, should only need &Blobby
-    mut blobbies: Query<
-        (Entity, &Transform, &mut Blobby, &Resources),
-        (Without<GameCursor>, Without<SelectedCursor>),
-    >,
+    blobbies: Query<(Entity, &Transform, &Blobby, &Resources, &BlobbyState), Without<GameCursor>>,
     model_assets: Res<ModelAssets>,
-    pickups: Query<(Entity, &Resources), With<Pickup>>,
-    dropoffs: Query<(&Dropoff, &Resources, &OutputResource), Without<OutgoingHats>>,
+    pickups: Query<(Entity, &Resources, Option<&Ore>), With<Pickup>>,
+    dropoffs: Query<
+        (
+            &Dropoff,
+            &Resources,
+            &OutputResource,
+            &ProcessTimer,
+            &Level,
+            &Item,
+            Option<&Retoolable>,
+        ),
+        Without<OutgoingHats>,
+    >,
     outgoing_hats: Query<&Dropoff, (With<OutgoingHats>, Without<OutputResource>)>,
+    economy: Res<Economy>,
+    rules: Res<GameSettings>,
 ) {
-    if let Some(mut trans) = selected_cursor.iter_mut().next() {
-        let mut set = false;
-        if let Some(selected_entity) = player.selected_entity {
-            if let Ok((_, selected_trans, _blobby, _resources)) = blobbies.get(selected_entity) {
-                trans.translation = selected_trans.translation + vec3(0.0, 0.0, 0.0);
-                set = true;
-            }
-        }
-        if !set {
-            trans.translation = vec3(0.0, -9999.0, 0.0);
-        }
-    }
-
     let mut cursor_pos = None;
     for intersection in &intersections {
         //info!(
@@ -536,38 +547,76 @@ pub fn mouse_interact(
     let cur_entity = b.board[cur_idx];
 
     if let Some(cur_entity) = cur_entity {
-        if let Ok((dropoff, _output_resource, output_kind)) = dropoffs.get(cur_entity) {
+        if let Ok((dropoff, output_resource, output_kind, timer, level, item, retool)) =
+            dropoffs.get(cur_entity)
+        {
             let id = &format!("{:?}", cur_entity);
             egui::show_tooltip(
                 egui_context.ctx_mut(),
                 egui::Id::new(format!("dropoff_hover{}", id)),
                 |ui| {
-                    let mut style = ui.style_mut();
+                    let style = ui.style_mut();
                     style.visuals.override_text_color = Some(TEXT_COLOR2);
-                    ui.label(&format!("MAKES {}", output_kind.0.name()));
+                    ui.label(format!("MAKES {}", output_kind.0.name()));
+                    if let Some(retool) = retool {
+                        if retool.changeover > 0 {
+                            ui.colored_label(
+                                WARNING_COLOR,
+                                format!("CHANGEOVER {} STEPS LEFT", retool.changeover),
+                            );
+                        }
+                    }
+                    ui.label(format!("LEVEL {}/{}", level.0, MAX_LEVEL));
+                    if player.upgrade_mode && level.0 < MAX_LEVEL {
+                        ui.label("UPGRADE COST");
+                        economy.upgrade_cost(*item, level.0).draw(
+                            &format!("upgrade{}", &id),
+                            ui,
+                            false,
+                            false,
+                            false,
+                        );
+                    }
                     ui.label("REQUIRES");
-                    output_kind
-                        .0
-                        .recipe()
-                        .draw(&format!("qty{}", &id), ui, false, false, false);
+                    let recipe = economy.recipe(output_kind.0);
+                    recipe.draw(&format!("qty{}", &id), ui, false, false, false);
+                    ui.label("YIELDS");
+                    economy.output(output_kind.0).draw(
+                        &format!("yield{}", &id),
+                        ui,
+                        false,
+                        false,
+                        false,
+                    );
                     ui.label("CONTAINS");
                     dropoff
                         .input
                         .draw(&format!("input{}", &id), ui, true, false, false);
-                    //ui.label("OUTPUT");
-                    //output_resource.draw(&format!("output{}", &id), ui, false, false, false);
+                    ui.label(format!(
+                        "{}/{} BATCHES QUEUED",
+                        dropoff.input.count_of(&recipe),
+                        dropoff.qty.count_of(&recipe)
+                    ));
+                    match timer.output_capacity {
+                        Some(capacity) => ui.label(format!("OUTPUT (HOLDS {} EACH)", capacity)),
+                        None => ui.label("OUTPUT"),
+                    };
+                    output_resource.draw(&format!("output{}", &id), ui, true, false, false);
+                    if timer.blocked {
+                        ui.colored_label(WARNING_COLOR, "BLOCKED: OUTPUT FULL");
+                    }
                 },
             );
         }
-        if let Ok((_, pickup_res)) = pickups.get(cur_entity) {
+        if let Ok((_, pickup_res, ore)) = pickups.get(cur_entity) {
             let id = &format!("{:?}", cur_entity);
             egui::show_tooltip(
                 egui_context.ctx_mut(),
                 egui::Id::new(format!("pickup_hover{}", id)),
                 |ui| {
-                    let mut style = ui.style_mut();
+                    let style = ui.style_mut();
                     style.visuals.override_text_color = Some(TEXT_COLOR2);
-                    ui.label("OUTPUT");
+                    ui.label(if ore.is_some() { "REMAINING" } else { "OUTPUT" });
                     pickup_res.draw(id, ui, false, false, false);
                 },
             );
@@ -578,7 +627,7 @@ pub fn mouse_interact(
                 egui_context.ctx_mut(),
                 egui::Id::new(format!("dropoff_hover{}", id)),
                 |ui| {
-                    let mut style = ui.style_mut();
+                    let style = ui.style_mut();
                     style.visuals.override_text_color = Some(TEXT_COLOR2);
                     ui.label("OUTGOING BIG HATS");
                     dropoff
@@ -595,7 +644,7 @@ pub fn mouse_interact(
     let mut hovered_blobby_pos = Vec3::ZERO;
     let ls_cur_pos_f = b.ws_vec3_to_ls_f(cursor_pos);
 
-    for (entity, trans, _, _) in &blobbies {
+    for (entity, trans, _, _, _) in &blobbies {
         let ls_trans = b.ws_vec3_to_ls_f(trans.translation);
         if ls_trans.distance(ls_cur_pos_f) < 0.7 {
             hovered_blobby = Some(entity);
@@ -604,9 +653,10 @@ pub fn mouse_interact(
     }
 
     if let Some(hovered_entity) = hovered_blobby {
-        if let Ok((_, _, blobby, resources)) = blobbies.get(hovered_entity) {
+        if let Ok((_, _, blobby, resources, state)) = blobbies.get(hovered_entity) {
             if let Some((mut trans, mut mesh)) = game_cursor.iter_mut().next() {
                 trans.translation = hovered_blobby_pos + vec3(0.0, 0.0, 0.0);
+                trans.scale = Vec3::ONE;
                 *mesh = model_assets.sphere_cursor.clone();
             }
             let id = format!("BLOBBY{}", blobby.id);
@@ -614,58 +664,261 @@ pub fn mouse_interact(
                 egui_context.ctx_mut(),
                 egui::Id::new(format!("blobby_hover{}", id)),
                 |ui| {
-                    let mut style = ui.style_mut();
+                    let style = ui.style_mut();
                     style.visuals.override_text_color = Some(TEXT_COLOR2);
                     ui.label(&id);
+                    ui.label(state.name());
+                    ui.label(format!("LOAD {}/{}", resources.total(), blobby.capacity));
+                    if rules.blobby_drain > 0.0 {
+                        ui.label(format!("CHARGE {:.0}%", blobby.charge * 100.0));
+                    }
+                    if blobby.auto {
+                        ui.label("AUTO");
+                    }
                     resources.draw(&id, ui, false, false, false);
                 },
             );
         }
     } else if let Some((mut trans, mut mesh)) = game_cursor.iter_mut().next() {
-        let p = b.ls_to_ws_vec3(b.ws_vec3_to_ls(cursor_pos));
-        trans.translation = p + vec3(0.0, -0.4, 0.0);
+        // Cover every cell the building would take up
+        let size = player
+            .item_to_place
+            .map(|item| economy.footprint(item))
+            .unwrap_or(IVec2::ONE);
+        let fp = Footprint::new(b.ws_vec3_to_ls(cursor_pos), size);
+        let fp_trans = b.footprint_transform(&fp);
+        trans.translation = fp_trans.translation + vec3(0.0, -0.4, 0.0);
+        trans.scale = vec3(fp_trans.scale.x, 1.0, fp_trans.scale.z);
         *mesh = model_assets.cube_cursor.clone();
     }
 
-    if buttons.just_pressed(MouseButton::Left) && (cursor_pos.y - 0.0).abs() < 0.1 {
-        if let Some(entity) = hovered_blobby {
-            if let Ok((entity, _, _blobby, _)) = blobbies.get(entity) {
-                player.selected_entity = Some(entity);
-                return;
+    let shift = keys.any_pressed([KeyCode::LShift, KeyCode::RShift]);
+    let on_board = (cursor_pos.y - 0.0).abs() < 0.1;
+    let (x, y) = (cur_ls_p.x as u8, cur_ls_p.y as u8);
+    let mut selected = blobbies
+        .iter_many(&player.selected)
+        .map(|(_, _, blobby, _, _)| blobby.id)
+        .collect::<Vec<_>>();
+    selected.sort();
+
+    // Click a pile and then the building it should feed to make a supply route, right
+    // click removes the routes of a pile or building
+    if player.route_mode {
+        *drag_start = None;
+        let on_pile = cur_entity.is_some_and(|entity| pickups.get(entity).is_ok());
+        if buttons.just_pressed(MouseButton::Left) && on_board {
+            let on_dropoff = cur_entity.is_some_and(|entity| {
+                dropoffs.get(entity).is_ok() || outgoing_hats.get(entity).is_ok()
+            });
+            match player.route_start {
+                Some(start) if on_dropoff && b.board[b.ls_to_idx(start)] != cur_entity => {
+                    let (sx, sy) = (start.x as u8, start.y as u8);
+                    action_queue.push(Action::RouteFrom(sx, sy, player.route_priority));
+                    action_queue.push(Action::RouteTo(x, y));
+                    player.route_start = None;
+                }
+                _ if on_pile => player.route_start = Some(cur_ls_p),
+                _ => player.route_start = None,
             }
         }
+        if buttons.just_pressed(MouseButton::Right) {
+            if cur_entity.is_some() {
+                action_queue.push(Action::ClearRoutes(x, y));
+            }
+            player.route_start = None;
+        }
+        return;
+    }
 
-        if let Some(cur_entity) = cur_entity {
-            if let Ok((pickup_entity, _)) = pickups.get(cur_entity) {
-                if let Some(entity) = player.selected_entity {
-                    // TODO use action system for replays
-                    if let Ok((_entity, _, mut blobby, _)) = blobbies.get_mut(entity) {
-                        blobby.resource_pile = Some(pickup_entity);
-                        return;
+    // Without a building or sell mode picked, left click acts on release so a drag can
+    // box select blobbies instead
+    let selecting = player.item_to_place.is_none()
+        && !player.sell_mode
+        && !player.upgrade_mode
+        && player.recipe_mode.is_none();
+    let mut click = false;
+    if selecting {
+        if buttons.just_pressed(MouseButton::Left) && on_board {
+            *drag_start = Some(ls_cur_pos_f);
+        }
+        if let Some(start) = *drag_start {
+            let dragged = start.distance(ls_cur_pos_f) > 0.5;
+            if dragged && buttons.pressed(MouseButton::Left) {
+                let ctx = egui_context.ctx_mut();
+                let corners = {
+                    let input = ctx.input();
+                    (input.pointer.press_origin(), input.pointer.hover_pos())
+                };
+                if let (Some(from), Some(to)) = corners {
+                    ctx.layer_painter(egui::LayerId::new(
+                        egui::Order::Foreground,
+                        egui::Id::new("box_select"),
+                    ))
+                    .rect_stroke(
+                        egui::Rect::from_two_pos(from, to),
+                        0.0,
+                        egui::Stroke::new(1.0, TEXT_COLOR2),
+                    );
+                }
+            }
+            if buttons.just_released(MouseButton::Left) {
+                *drag_start = None;
+                if dragged {
+                    let (min, max) = (start.min(ls_cur_pos_f), start.max(ls_cur_pos_f));
+                    if !shift {
+                        player.selected.clear();
+                    }
+                    for (entity, trans, _, _, _) in &blobbies {
+                        let ls = b.ws_vec3_to_ls_f(trans.translation);
+                        let inside = ls.cmpge(min).all() && ls.cmple(max).all();
+                        if inside && !player.selected.contains(&entity) {
+                            player.selected.push(entity);
+                        }
                     }
+                } else {
+                    click = true;
+                }
+            }
+        }
+    } else {
+        *drag_start = None;
+        click = buttons.just_pressed(MouseButton::Left) && on_board;
+    }
+
+    if click {
+        if let Some(entity) = hovered_blobby {
+            // Shift click adds or removes a blobby from the selection
+            if shift {
+                if let Some(i) = player.selected.iter().position(|e| *e == entity) {
+                    player.selected.remove(i);
+                } else {
+                    player.selected.push(entity);
                 }
+            } else {
+                player.selected = vec![entity];
             }
+            return;
         }
 
-        if let Some(entity) = player.selected_entity {
-            if let Ok((_entity, _, blobby, _)) = blobbies.get(entity) {
-                action_queue.push(Action::MoveBlobby(
-                    cur_ls_p.x as u8,
-                    cur_ls_p.y as u8,
-                    blobby.id,
-                ));
-                return;
+        let on_pile = cur_entity.is_some_and(|entity| pickups.get(entity).is_ok());
+        if !selected.is_empty() {
+            if on_pile {
+                for id in &selected {
+                    if shift {
+                        action_queue.push(Action::QueuePickup(x, y, *id));
+                    } else {
+                        action_queue.push(Action::AssignPile(x, y, *id));
+                    }
+                }
+            } else {
+                // Spread the group over the free cells around the clicked one
+                let cells = b.formation(cur_ls_p, selected.len());
+                for (id, cell) in selected.iter().zip(cells) {
+                    let (x, y) = (cell.x as u8, cell.y as u8);
+                    if shift {
+                        action_queue.push(Action::QueueMove(x, y, *id));
+                    } else {
+                        action_queue.push(Action::MoveBlobby(x, y, *id));
+                    }
+                }
             }
+            return;
         }
 
         if player.sell_mode {
-            action_queue.push(Action::SellItem(cur_ls_p.x as u8, cur_ls_p.y as u8));
+            action_queue.push(Action::SellItem(x, y));
+        } else if player.upgrade_mode {
+            action_queue.push(Action::Upgrade(x, y));
+        } else if let Some(kind) = player.recipe_mode {
+            let r = R::ALL.iter().position(|r| *r == kind).unwrap() as u8;
+            action_queue.push(Action::SetRecipe(x, y, r));
         } else if let Some(selected_item) = player.item_to_place {
-            let x = cur_ls_p.x as u8;
-            let y = cur_ls_p.y as u8;
             action_queue.push(Action::Place(x, y, selected_item as u8));
         }
     }
+
+    if buttons.just_pressed(MouseButton::Right) {
+        for id in &selected {
+            if shift {
+                if cur_entity.is_some() {
+                    action_queue.push(Action::QueueDropoff(x, y, *id));
+                }
+            } else {
+                action_queue.push(Action::UnassignPile(*id));
+            }
+        }
+    }
+}
+
+/// Number keys select a control group, ctrl + number saves the selection to it
+pub fn control_groups(
+    mut egui_context: ResMut<EguiContext>,
+    keys: Res<Input<KeyCode>>,
+    mut player: ResMut<PlayerState>,
+    blobbies: Query<Entity, With<Blobby>>,
+) {
+    if egui_context.ctx_mut().wants_keyboard_input() {
+        return;
+    }
+    let ctrl = keys.any_pressed([KeyCode::LControl, KeyCode::RControl]);
+    let numbers = [
+        KeyCode::Key0,
+        KeyCode::Key1,
+        KeyCode::Key2,
+        KeyCode::Key3,
+        KeyCode::Key4,
+        KeyCode::Key5,
+        KeyCode::Key6,
+        KeyCode::Key7,
+        KeyCode::Key8,
+        KeyCode::Key9,
+    ];
+    for (group, key) in numbers.into_iter().enumerate() {
+        if keys.just_pressed(key) {
+            if ctrl {
+                player.control_groups[group] = player.selected.clone();
+            } else {
+                // Drop blobbies from the group that no longer exist
+                let alive = blobbies
+                    .iter_many(&player.control_groups[group])
+                    .collect::<Vec<_>>();
+                player.control_groups[group] = alive.clone();
+                player.selected = alive;
+            }
+        }
+    }
+}
+
+/// Keeps one selection marker on each selected blobby, spawning more markers as needed
+pub fn update_selection_markers(
+    mut com: Commands,
+    player: Res<PlayerState>,
+    blobbies: Query<&Transform, (With<Blobby>, Without<SelectedCursor>)>,
+    mut markers: Query<
+        (&mut Transform, &Handle<Mesh>, &Handle<StandardMaterial>),
+        With<SelectedCursor>,
+    >,
+) {
+    let mut positions = blobbies.iter_many(&player.selected);
+    let mut template = None;
+    for (mut trans, mesh, material) in &mut markers {
+        trans.translation = match positions.next() {
+            Some(blobby_trans) => blobby_trans.translation,
+            None => vec3(0.0, -9999.0, 0.0),
+        };
+        template = Some((mesh.clone(), material.clone()));
+    }
+    if let Some((mesh, material)) = template {
+        for blobby_trans in positions {
+            com.spawn(PbrBundle {
+                mesh: mesh.clone(),
+                material: material.clone(),
+                transform: Transform::from_translation(blobby_trans.translation),
+                ..default()
+            })
+            .insert(SelectedCursor);
+        }
+    }
 }
 
 #[derive(Component)]
@@ -701,31 +954,96 @@ pub fn update_player_resources(
 
 // TODO move elsewhere
 pub fn process_factories(
+    mut com: Commands,
+    economy: Res<Economy>,
     mut query: Query<(
+        Entity,
         &mut Resources,
         &mut ProcessTimer,
         &mut Dropoff,
-        &OutputResource,
+        &mut OutputResource,
+        &Level,
+        &Item,
+        ChangeTrackers<Level>,
+        Option<&mut Retoolable>,
     )>,
 ) {
-    for (mut resources, mut timer, mut dropoff, output) in query.iter_mut() {
-        if timer.started {
+    for (
+        entity,
+        mut resources,
+        mut timer,
+        mut dropoff,
+        mut output,
+        level,
+        item,
+        level_changed,
+        mut retool,
+    ) in query.iter_mut()
+    {
+        let next = retool.as_mut().and_then(|retool| retool.next.take());
+        if let Some(next) = next {
+            // All input, along with that of a cycle under way, stays in the building for
+            // whichever recipe uses it. It is not collected or spent by the player.
+            if timer.started {
+                for (k, v) in economy.recipe(output.0).0 {
+                    *dropoff.input.0.entry(k).or_insert(0) += v;
+                }
+            }
+            output.0 = next;
+            if picked_up(next) {
+                com.entity(entity).insert(Pickup);
+            } else {
+                com.entity(entity).remove::<Pickup>();
+            }
+            timer.started = false;
+            timer.time = 0;
+            if let Some(retool) = retool.as_mut() {
+                retool.changeover = economy.changeover(*item);
+            }
+        }
+        if economy.is_changed() || level_changed.is_changed() || next.is_some() {
+            // Keep existing buildings in sync when the economy file is reloaded, they are
+            // upgraded or their recipe is changed
+            let recipe = economy.recipe(output.0);
+            for k in recipe.0.keys() {
+                dropoff.input.0.entry(*k).or_insert(0);
+            }
+            for k in economy.output(output.0).0.keys() {
+                resources.0.entry(*k).or_insert(0);
+            }
+            dropoff.qty = economy.input_buffer(*item, output.0, level.0);
+            timer.length = economy.process_time(output.0, level.0);
+            timer.output_capacity = economy.output_capacity(*item, level.0);
+        }
+        if let Some(retool) = retool.as_mut().filter(|retool| retool.changeover > 0) {
+            retool.changeover -= 1;
+            timer.blocked = false;
+        } else if timer.started {
             timer.time += 1;
             if timer.time >= timer.length {
-                // add one to the output and reset the timer
-                let mut out = Resources::zero();
-                out.0.insert(output.0, 1);
-                *resources = resources.sum(&out);
+                // add the output and byproducts and reset the timer
+                *resources = resources.sum(&economy.output(output.0));
                 timer.started = false;
                 timer.time = 0;
             }
-        } else if dropoff
-            .input
-            .take(&output.0.recipe(), &mut Resources::zero(), true)
-        {
-            // the resources were available start the timer
-            timer.started = true;
-            timer.time = 0;
+        } else {
+            // Nothing new is started until some of each full output is collected
+            timer.blocked = timer.output_capacity.is_some_and(|capacity| {
+                economy
+                    .output(output.0)
+                    .0
+                    .keys()
+                    .any(|k| resources.0.get(k).copied().unwrap_or(0) >= capacity)
+            });
+            if !timer.blocked
+                && dropoff
+                    .input
+                    .take(&economy.recipe(output.0), &mut Resources::zero(), true)
+            {
+                // the resources were available start the timer
+                timer.started = true;
+                timer.time = 0;
+            }
         }
     }
 }
@@ -736,10 +1054,14 @@ pub fn hats_objective(
     mut point_lights: Query<(Entity, &mut PointLight)>,
     mut spot_lights: Query<(Entity, &mut SpotLight)>,
 ) {
-    let delivered_hats = outgoing_hats.single().input.0.get(&R::BigHats).unwrap();
-    if *delivered_hats >= player.required_hats {
-        player.delivery_dealine = (50000.0 - 4000.0 * *delivered_hats as f64)
-            .max(1500.0 / (*delivered_hats as f64 + 10.0).log(3.0));
+    // Replays can place more than one depot, hats delivered to any of them count
+    let delivered_hats = outgoing_hats
+        .iter()
+        .map(|dropoff| dropoff.input.0.get(&R::BigHats).copied().unwrap_or(0))
+        .sum::<u64>();
+    if delivered_hats >= player.required_hats {
+        player.delivery_dealine = (50000.0 - 4000.0 * delivered_hats as f64)
+            .max(1500.0 / (delivered_hats as f64 + 10.0).log(3.0));
         player.required_hats = delivered_hats + 1;
     }
 
@@ -754,3 +1076,101 @@ pub fn hats_objective(
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{items::PlasticReceiver, schedule::tests::headless_app};
+
+    /// Builds `item` on the first free cell near the warehouse where it is allowed
+    fn build(app: &mut App, item: Item) -> (IVec2, Entity) {
+        let warehouse = app
+            .world
+            .query_filtered::<&Footprint, With<PlasticReceiver>>()
+            .single(&app.world)
+            .access_tile();
+        let cells = app.world.resource::<GameBoard>().formation(warehouse, 40);
+        for ls in cells.into_iter().skip(1) {
+            let mut queue = app.world.resource_mut::<ActionQueue>();
+            queue.push(Action::CheatCredits);
+            queue.push(Action::Place(ls.x as u8, ls.y as u8, item as u8));
+            app.update();
+            let b = app.world.resource::<GameBoard>();
+            if let Some(entity) = b.board[b.ls_to_idx(ls)] {
+                return (ls, entity);
+            }
+        }
+        panic!("nowhere to build {:?}", item);
+    }
+
+    fn resources(pairs: &[(R, u64)]) -> Resources {
+        Resources(pairs.iter().copied().collect())
+    }
+
+    #[test]
+    fn retooled_factory_keeps_its_input() {
+        let mut app = headless_app();
+        app.update();
+        app.update();
+
+        let (ls, factory) = build(&mut app, Item::Factory);
+        // Two batteries worth of input, one of which goes into a cycle right away
+        app.world.get_mut::<Dropoff>(factory).unwrap().input =
+            resources(&[(R::Copper, 4), (R::Lithium, 4)]);
+        app.update();
+        assert!(app.world.get::<ProcessTimer>(factory).unwrap().started);
+        let before = app
+            .world
+            .resource::<PlayerState>()
+            .combined_resources
+            .clone();
+
+        let little_hats = R::ALL.iter().position(|r| *r == R::LittleHats).unwrap() as u8;
+        app.world
+            .resource_mut::<ActionQueue>()
+            .push(Action::SetRecipe(ls.x as u8, ls.y as u8, little_hats));
+        app.update();
+        app.update();
+
+        let input = &app.world.get::<Dropoff>(factory).unwrap().input;
+        assert_eq!(input.0.get(&R::Copper), Some(&4));
+        assert_eq!(input.0.get(&R::Lithium), Some(&4));
+        let after = &app.world.resource::<PlayerState>().combined_resources;
+        for r in [R::Copper, R::Lithium] {
+            assert_eq!(after.0.get(&r), before.0.get(&r));
+        }
+    }
+
+    #[test]
+    fn factory_blocks_while_any_output_is_full() {
+        let mut app = headless_app();
+        app.update();
+        app.update();
+
+        app.world
+            .resource_mut::<Economy>()
+            .items
+            .get_mut(&Item::GlassRefinery)
+            .unwrap()
+            .output_capacity = Some(2);
+        let (_, refinery) = build(&mut app, Item::GlassRefinery);
+        // Only the sand byproduct is full
+        *app.world.get_mut::<Resources>(refinery).unwrap() =
+            resources(&[(R::Glass, 0), (R::Sand, 2)]);
+        app.world.get_mut::<Dropoff>(refinery).unwrap().input = resources(&[(R::Sand, 3)]);
+        app.update();
+        let timer = app.world.get::<ProcessTimer>(refinery).unwrap();
+        assert!(timer.blocked);
+        assert!(!timer.started);
+
+        app.world
+            .get_mut::<Resources>(refinery)
+            .unwrap()
+            .0
+            .insert(R::Sand, 1);
+        app.update();
+        let timer = app.world.get::<ProcessTimer>(refinery).unwrap();
+        assert!(!timer.blocked);
+        assert!(timer.started);
+    }
+}
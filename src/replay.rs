@@ -0,0 +1,265 @@
+use std::fmt;
+
+use bytecheck::CheckBytes;
+use lz4_flex::{block::DecompressError, compress_prepend_size, decompress_size_prepended};
+use rkyv::{AlignedVec, Archive, Deserialize, Serialize};
+
+use crate::{action::ActionRecording, economy::Economy, player::GameSettings};
+
+/// Written in front of every replay, bump when `ReplayHeader`, `GameSettings` or the
+/// encoding of `Action` changes so old replays are rejected instead of misread.
+pub const REPLAY_FORMAT_VERSION: u32 = 6;
+
+#[derive(Archive, Deserialize, Serialize, Clone, PartialEq, Debug)]
+#[archive_attr(derive(CheckBytes))]
+pub struct ReplayHeader {
+    pub seed: u64,
+    pub rules: GameSettings,
+    // The economy file the game was played with
+    pub economy: String,
+    // Informational only, replays are compatible as long as the format version matches
+    pub build_version: String,
+}
+
+impl ReplayHeader {
+    pub fn new(seed: u64, rules: &GameSettings, economy: &Economy) -> Self {
+        ReplayHeader {
+            seed,
+            rules: rules.clone(),
+            economy: economy.source.clone(),
+            build_version: env!("CARGO_PKG_VERSION").to_string(),
+        }
+    }
+
+    pub fn economy(&self) -> Result<Economy, bevy::asset::Error> {
+        Economy::from_ron(self.economy.as_bytes())
+    }
+}
+
+#[derive(Archive, Deserialize, Serialize)]
+#[archive_attr(derive(CheckBytes))]
+struct Replay {
+    header: ReplayHeader,
+    actions: ActionRecording,
+}
+
+#[derive(Debug)]
+pub enum ReplayError {
+    Base64(base64::DecodeError),
+    Lz4(DecompressError),
+    Truncated,
+    Version { found: u32, expected: u32 },
+    Archive(String),
+}
+
+impl fmt::Display for ReplayError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReplayError::Base64(e) => write!(f, "replay is not valid base64: {e}"),
+            ReplayError::Lz4(e) => write!(f, "replay could not be decompressed: {e}"),
+            ReplayError::Truncated => write!(f, "replay is too short to contain a header"),
+            ReplayError::Version { found, expected } => write!(
+                f,
+                "replay format version {found} is not supported, expected {expected}"
+            ),
+            ReplayError::Archive(e) => write!(f, "replay data is invalid: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for ReplayError {}
+
+impl ActionRecording {
+    /// base64(lz4(format version as u32 le + rkyv archive of header and actions))
+    pub fn encode(&self, header: &ReplayHeader) -> String {
+        let replay = Replay {
+            header: header.clone(),
+            actions: self.clone(),
+        };
+        let archive = rkyv::to_bytes::<_, 1024>(&replay).unwrap();
+        let mut bytes = REPLAY_FORMAT_VERSION.to_le_bytes().to_vec();
+        bytes.extend_from_slice(&archive);
+        base64::encode(compress_prepend_size(&bytes))
+    }
+
+    pub fn decode(replay: &str) -> Result<(ReplayHeader, ActionRecording), ReplayError> {
+        let compressed = base64::decode(replay.trim()).map_err(ReplayError::Base64)?;
+        let bytes = decompress_size_prepended(&compressed).map_err(ReplayError::Lz4)?;
+        if bytes.len() < 4 {
+            return Err(ReplayError::Truncated);
+        }
+        let found = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        if found != REPLAY_FORMAT_VERSION {
+            return Err(ReplayError::Version {
+                found,
+                expected: REPLAY_FORMAT_VERSION,
+            });
+        }
+
+        // The archive needs to be aligned again after skipping the version
+        let mut archive = AlignedVec::new();
+        archive.extend_from_slice(&bytes[4..]);
+        let archived = rkyv::check_archived_root::<Replay>(&archive)
+            .map_err(|e| ReplayError::Archive(format!("{e:?}")))?;
+        let replay: Replay = archived.deserialize(&mut rkyv::Infallible).unwrap();
+        Ok((replay.header, replay.actions))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::prelude::*;
+
+    use super::*;
+    use crate::{
+        action::{Action, ActionQueue, GameRecorder},
+        board::{Footprint, GameBoard},
+        items::{Blobby, Item, PlasticReceiver},
+        player::{PlayerState, GAMESETTINGS},
+        schedule::tests::headless_app,
+        MapSeed,
+    };
+
+    fn header() -> ReplayHeader {
+        ReplayHeader::new(1234, &GAMESETTINGS, &Economy::default())
+    }
+
+    fn encode_bytes(bytes: &[u8]) -> String {
+        base64::encode(compress_prepend_size(bytes))
+    }
+
+    #[test]
+    fn roundtrip() {
+        let actions = ActionRecording(vec![(0, [9, 3, 4, 0]), (120, [10, 5, 6, 1])]);
+        let replay = actions.encode(&header());
+        let (decoded_header, decoded_actions) = ActionRecording::decode(&replay).unwrap();
+        assert_eq!(decoded_header, header());
+        assert_eq!(decoded_actions, actions);
+    }
+
+    #[test]
+    fn bad_base64() {
+        let err = ActionRecording::decode("not base64!").unwrap_err();
+        assert!(matches!(err, ReplayError::Base64(_)));
+    }
+
+    #[test]
+    fn bad_lz4() {
+        // Claims 16 decompressed bytes but holds none of them
+        let replay = base64::encode([16, 0, 0, 0, 0xff]);
+        let err = ActionRecording::decode(&replay).unwrap_err();
+        assert!(matches!(err, ReplayError::Lz4(_)));
+    }
+
+    #[test]
+    fn truncated() {
+        let err = ActionRecording::decode(&encode_bytes(&[1, 2])).unwrap_err();
+        assert!(matches!(err, ReplayError::Truncated));
+    }
+
+    #[test]
+    fn wrong_version() {
+        let mut bytes = (REPLAY_FORMAT_VERSION + 1).to_le_bytes().to_vec();
+        bytes.extend_from_slice(&[0; 16]);
+        let err = ActionRecording::decode(&encode_bytes(&bytes)).unwrap_err();
+        assert!(matches!(
+            err,
+            ReplayError::Version { found, expected }
+                if found == REPLAY_FORMAT_VERSION + 1 && expected == REPLAY_FORMAT_VERSION
+        ));
+    }
+
+    #[test]
+    fn failed_archive_check() {
+        let mut bytes = REPLAY_FORMAT_VERSION.to_le_bytes().to_vec();
+        bytes.extend_from_slice(&[0xff; 3]);
+        let err = ActionRecording::decode(&encode_bytes(&bytes)).unwrap_err();
+        assert!(matches!(err, ReplayError::Archive(_)));
+    }
+
+    /// Where every blobby is, by id
+    fn blobby_cells(app: &mut App) -> Vec<(u8, IVec2)> {
+        let mut query = app.world.query::<(&Blobby, &Transform)>();
+        let b = app.world.resource::<GameBoard>();
+        let mut cells = query
+            .iter(&app.world)
+            .map(|(blobby, trans)| (blobby.id, b.ws_vec3_to_ls(trans.translation)))
+            .collect::<Vec<_>>();
+        cells.sort_by_key(|(id, _)| *id);
+        cells
+    }
+
+    #[test]
+    fn recorded_game_plays_back_the_same() {
+        let mut app = headless_app();
+        app.update();
+        app.update();
+
+        // A refinery and a few auto blobbies next to the warehouse, so they have to share
+        // cells on their way
+        let warehouse = app
+            .world
+            .query_filtered::<&Footprint, With<PlasticReceiver>>()
+            .single(&app.world)
+            .access_tile();
+        let cells = app.world.resource::<GameBoard>().formation(warehouse, 12);
+        let mut queue = app.world.resource_mut::<ActionQueue>();
+        queue.push(Action::CheatCredits);
+        queue.push(Action::Place(
+            cells[11].x as u8,
+            cells[11].y as u8,
+            Item::CopperRefinery as u8,
+        ));
+        for cell in &cells[1..4] {
+            queue.push(Action::Place(
+                cell.x as u8,
+                cell.y as u8,
+                Item::Blobby as u8,
+            ));
+        }
+        app.update();
+        app.world
+            .resource_mut::<ActionQueue>()
+            .push(Action::SetAuto(1, 0));
+        for _ in 0..1500 {
+            app.update();
+        }
+
+        let step = app.world.resource::<PlayerState>().step;
+        let resources = app
+            .world
+            .resource::<PlayerState>()
+            .combined_resources
+            .clone();
+        let blobbies = blobby_cells(&mut app);
+        assert_eq!(blobbies.len(), 3);
+        let header = ReplayHeader::new(
+            app.world.resource::<MapSeed>().0,
+            app.world.resource::<GameSettings>(),
+            app.world.resource::<Economy>(),
+        );
+        let replay = app.world.resource::<GameRecorder>().actions.encode(&header);
+
+        let (header, actions) = ActionRecording::decode(&replay).unwrap();
+        let mut replayed = headless_app();
+        replayed
+            .insert_resource(header.rules.clone())
+            .insert_resource(MapSeed(header.seed))
+            .insert_resource(header.economy().unwrap())
+            .insert_resource(GameRecorder {
+                actions,
+                header: Some(header),
+                disable_rec: true,
+                play: true,
+                play_head: 0,
+            });
+        while replayed.world.resource::<PlayerState>().step < step {
+            replayed.update();
+        }
+
+        let player = replayed.world.resource::<PlayerState>();
+        assert_eq!(player.step, step);
+        assert_eq!(player.combined_resources.0, resources.0);
+        assert_eq!(blobby_cells(&mut replayed), blobbies);
+    }
+}
@@ -6,81 +6,230 @@ use bevy_system_graph::SystemGraph;
 use iyes_loopless::prelude::*;
 
 use crate::{
-    action::*, game_state_run_level_unpaused, items::*, player::*, restart_game, GameState,
+    action::*,
+    board::GameBoard,
+    economy::{Economy, PendingEconomy},
+    game_state_run_level_unpaused,
+    items::*,
+    player::*,
+    restart_game, setup_game, GameRng, GameState, MapSeed, PausedState, RestartGame,
 };
 
 pub const TIMESTEP_MILLI: u64 = 16;
 pub const TIMESTEP: f32 = 0.016;
 pub const TIMESTEP_SEC_F64: f64 = 0.016;
 
-pub(crate) fn setup_schedule(app: &mut bevy::prelude::App) {
-    let mut fixed_update_stage = SystemStage::parallel();
+/// The game simulation without rendering, egui or audio.
+///
+/// With `headless` set the plugin also registers the game states, starting directly in
+/// `GameState::RunLevel`, and runs one simulation step per `App::update` instead of on a
+/// fixed wall clock timestep. This is what tools, tests and servers should use together
+/// with `MinimalPlugins`.
+#[derive(Default)]
+pub struct HarvestSimPlugin {
+    pub headless: bool,
+}
+
+impl Plugin for HarvestSimPlugin {
+    fn build(&self, app: &mut App) {
+        if self.headless {
+            app.add_loopless_state(GameState::RunLevel)
+                .add_loopless_state(PausedState::Unpaused);
+        }
+
+        app.insert_resource(GameBoard::default())
+            .insert_resource(RestartGame::default())
+            .insert_resource(GameRng::default())
+            .init_resource::<MapSeed>()
+            .init_resource::<Economy>()
+            .init_resource::<PendingEconomy>()
+            .init_resource::<SupplyRoutes>()
+            .insert_resource(PlayerState::default())
+            .insert_resource(GAMESETTINGS)
+            .insert_resource(ActionQueue::default())
+            .insert_resource(GameRecorder::default())
+            .add_event::<BlobbyStateChanged>()
+            .add_enter_system(GameState::RunLevel, setup_game);
+
+        let mut fixed_update_stage = SystemStage::parallel();
 
+        fixed_update_stage.add_system_set(
+            Into::<SystemSet>::into(SystemGraph::new().root(set_level).graph())
+                .with_run_criteria(game_state_run_level_unpaused)
+                .label("STEP PLAYER")
+                .before("STEP BLOBBY"),
+        );
+
+        fixed_update_stage.add_system_set(
+            Into::<SystemSet>::into(
+                SystemGraph::new()
+                    .root(receive_plastic)
+                    .then(auto_assign_blobbies)
+                    .then(charge_blobbies)
+                    .then(follow_orders)
+                    .then(blobby_get_resource)
+                    .then(deplete_ore)
+                    .then(blobby_put_resource)
+                    .then(update_blobby_paths)
+                    .then(move_blobby_along_path)
+                    .then(update_blobby_states)
+                    .then(process_factories)
+                    .then(update_player_resources)
+                    .then(hats_objective)
+                    //.then(debug_show_blobby_path)
+                    .graph(),
+            )
+            .with_run_criteria(game_state_run_level_unpaused)
+            .label("STEP BLOBBY"),
+        );
+
+        fixed_update_stage.add_system_set(
+            ConditionSet::new()
+                .run_in_state(GameState::RunLevel)
+                .label("STEP ACTION")
+                .after("STEP BLOBBY")
+                .with_system(process_actions)
+                .into(),
+        );
+
+        fixed_update_stage.add_system_set(
+            ConditionSet::new()
+                .run_in_state(GameState::RunLevel)
+                .label("STEP RESTART GAME")
+                .after("STEP ACTION")
+                .with_system(restart_game)
+                .into(),
+        );
+
+        if self.headless {
+            app.add_stage_after(CoreStage::Update, "my_fixed_update", fixed_update_stage);
+        } else {
+            app.add_stage_after(
+                CoreStage::Update,
+                "my_fixed_update",
+                FixedTimestepStage::new(Duration::from_millis(TIMESTEP_MILLI), "main")
+                    .with_stage(fixed_update_stage),
+            );
+        }
+    }
+}
+
+pub(crate) fn setup_schedule(app: &mut bevy::prelude::App) {
     app.add_system_set(
         ConditionSet::new()
             .label("mouse_interact")
             .run_in_state(GameState::RunLevel)
             .with_system(mouse_interact)
+            .with_system(control_groups)
+            .with_system(update_selection_markers)
             .into(),
     );
 
     app.add_plugin(DefaultRaycastingPlugin::<MyRaycastSet>::default())
-        .insert_resource(PlayerState::default())
         .add_enter_system(GameState::RunLevel, setup_player)
         .add_system_to_stage(
             CoreStage::First,
             update_raycast_with_cursor.before(RaycastSystem::BuildRays::<MyRaycastSet>),
         );
+}
 
-    fixed_update_stage.add_system_set(
-        Into::<SystemSet>::into(SystemGraph::new().root(set_level).graph())
-            .with_run_criteria(game_state_run_level_unpaused)
-            .label("STEP PLAYER")
-            .before("STEP BLOBBY"),
-    );
+#[cfg(test)]
+pub(crate) mod tests {
+    use bevy::ecs::event::ManualEventReader;
 
-    fixed_update_stage.add_system_set(
-        Into::<SystemSet>::into(
-            SystemGraph::new()
-                .root(receive_plastic)
-                .then(blobby_get_resource)
-                .then(blobby_put_resource)
-                .then(update_blobby_paths)
-                .then(move_blobby_along_path)
-                .then(process_factories)
-                .then(update_player_resources)
-                .then(hats_objective)
-                //.then(debug_show_blobby_path)
-                .graph(),
-        )
-        .with_run_criteria(game_state_run_level_unpaused)
-        .label("STEP BLOBBY"),
-    );
+    use super::*;
+    use crate::board::Footprint;
 
-    app.insert_resource(ActionQueue::default());
-    app.insert_resource(GameRecorder::default());
-    fixed_update_stage.add_system_set(
-        ConditionSet::new()
-            .run_in_state(GameState::RunLevel)
-            .label("STEP ACTION")
-            .after("STEP BLOBBY")
-            .with_system(process_actions)
-            .into(),
-    );
+    /// The simulation on its own, the way tools and servers run it
+    pub(crate) fn headless_app() -> App {
+        let mut app = App::new();
+        app.add_plugins(MinimalPlugins)
+            .add_plugin(HarvestSimPlugin { headless: true });
+        app
+    }
 
-    fixed_update_stage.add_system_set(
-        ConditionSet::new()
-            .run_in_state(GameState::RunLevel)
-            .label("STEP RESTART GAME")
-            .after("STEP ACTION")
-            .with_system(restart_game)
-            .into(),
-    );
+    #[test]
+    fn headless_app_sets_up_the_game() {
+        let mut app = headless_app();
+        for _ in 0..3 {
+            app.update();
+        }
 
-    app.add_stage_after(
-        CoreStage::Update,
-        "my_fixed_update",
-        FixedTimestepStage::new(Duration::from_millis(TIMESTEP_MILLI), "main")
-            .with_stage(fixed_update_stage),
-    );
+        let warehouses = app
+            .world
+            .query_filtered::<(), With<PlasticReceiver>>()
+            .iter(&app.world)
+            .count();
+        assert_eq!(warehouses, 1);
+        let deposits = app
+            .world
+            .query_filtered::<(), With<Ore>>()
+            .iter(&app.world)
+            .count();
+        assert!(deposits > 0);
+        assert!(app.world.resource::<PlayerState>().step > 0);
+    }
+
+    #[test]
+    fn assigned_blobby_goes_through_its_states() {
+        let mut app = headless_app();
+        app.update();
+        app.update();
+
+        // A copper or lithium deposit, a refinery for it well out of reach of the deposit and a
+        // blobby that has to walk to the deposit first
+        let mut deposits = app
+            .world
+            .query_filtered::<(&Footprint, &Resources), With<Ore>>()
+            .iter(&app.world)
+            .filter_map(|(fp, res)| {
+                if res.0.contains_key(&R::CopperOre) {
+                    Some(((fp.origin.x, fp.origin.y), Item::CopperRefinery))
+                } else if res.0.contains_key(&R::LithiumOre) {
+                    Some(((fp.origin.x, fp.origin.y), Item::LithiumRefinery))
+                } else {
+                    None
+                }
+            })
+            .collect::<Vec<_>>();
+        deposits.sort_by_key(|(origin, _)| *origin);
+        let ((x, y), refinery) = deposits[0];
+        let pile = IVec2::new(x, y);
+        let cells = app.world.resource::<GameBoard>().formation(pile, 200);
+        let site = *cells
+            .iter()
+            .find(|cell| (**cell - pile).as_vec2().length() >= 8.0)
+            .unwrap();
+        let start = cells[20];
+        let mut queue = app.world.resource_mut::<ActionQueue>();
+        queue.push(Action::Place(site.x as u8, site.y as u8, refinery as u8));
+        queue.push(Action::Place(
+            start.x as u8,
+            start.y as u8,
+            Item::Blobby as u8,
+        ));
+        app.update();
+        app.world
+            .resource_mut::<ActionQueue>()
+            .push(Action::AssignPile(x as u8, y as u8, 1));
+
+        let mut reader = ManualEventReader::<BlobbyStateChanged>::default();
+        let mut states = Vec::new();
+        for _ in 0..2000 {
+            app.update();
+            let events = app.world.resource::<Events<BlobbyStateChanged>>();
+            states.extend(reader.iter(events).map(|event| event.to));
+            if states.contains(&BlobbyState::MovingToDropoff) {
+                break;
+            }
+        }
+        assert_eq!(
+            states,
+            [
+                BlobbyState::MovingToPile,
+                BlobbyState::Loading,
+                BlobbyState::MovingToDropoff
+            ]
+        );
+    }
 }
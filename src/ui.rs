@@ -11,15 +11,25 @@ use crate::action::ActionRecording;
 use crate::action::GameRecorder;
 use crate::audio::AudioEvents;
 use crate::audio::MUSIC_LEVEL_CHANGED;
+use crate::board::Footprint;
+use crate::board::GameBoard;
+use crate::economy::Economy;
 //use crate::audio::SFX_LEVEL_CHANGED;
 
 use crate::GameState;
 
+use crate::items::Blobby;
+use crate::items::BlobbyState;
 use crate::items::Dropoff;
 use crate::items::Item;
+use crate::items::Orders;
 use crate::items::OutgoingHats;
+use crate::items::SupplyRoutes;
+use crate::player::GameSettings;
 use crate::player::PlayerState;
 use crate::player::R;
+use crate::replay::ReplayHeader;
+use crate::MapSeed;
 
 pub struct GameUI;
 impl Plugin for GameUI {
@@ -32,6 +42,7 @@ impl Plugin for GameUI {
                     .run_in_state(GameState::RunLevel)
                     .with_system(ui_sidebar)
                     .with_system(ui_sidebar_left)
+                    .with_system(ui_supply_routes)
                     .into(),
             )
             .add_startup_system(setup_fonts);
@@ -42,6 +53,7 @@ pub const SELECTED_COLOR: Color32 = Color32::from_rgb(255 / 2, 160 / 2, 98 / 2);
 pub const DESELECTED_COLOR: Color32 = Color32::from_rgb(255 / 8, 160 / 8, 98 / 8);
 pub const TEXT_COLOR: Color32 = Color32::from_rgb(255, 200, 145);
 pub const TEXT_COLOR2: Color32 = Color32::from_rgb(140, 170, 170);
+pub const WARNING_COLOR: Color32 = Color32::from_rgb(255, 110, 90);
 
 fn select_button(ui: &mut egui::Ui, text: &str, selected: bool) -> egui::Response {
     ui.add(egui::Button::new(text).fill(if selected {
@@ -57,24 +69,30 @@ fn ui_buy_button(
     message: &str,
     item: Item,
     player: &mut PlayerState,
+    economy: &Economy,
 ) {
     let response = select_button(ui, message, player.item_to_place == Some(item));
     if response.clicked() {
         player.item_to_place = Some(item);
         player.sell_mode = false;
-        player.selected_entity = None;
+        player.upgrade_mode = false;
+        player.recipe_mode = None;
+        player.selected.clear();
+        player.route_mode = false;
+        player.placement_refused = None;
     }
     if response.hovered() {
         egui::show_tooltip(
             ctx.ctx_mut(),
             egui::Id::new(format!("cost_hover{}", message)),
             |ui| {
-                let mut style = ui.style_mut();
+                let style = ui.style_mut();
                 style.visuals.override_text_color = Some(TEXT_COLOR2);
                 ui.label("BUILD COST");
-                item.cost()
+                economy
+                    .cost(item)
                     .draw(&format!("item_cost{}", message), ui, false, false, false);
-                if let Some((input, output)) = item.recipe() {
+                if let Some((input, output)) = economy.item_recipe(item) {
                     ui.label("INPUT");
                     input.draw(&format!("input_cost{}", message), ui, false, false, false);
                     ui.label("OUTPUT");
@@ -93,14 +111,21 @@ fn ui_sidebar(
     mut audio_events: ResMut<AudioEvents>,
     mut action_queue: ResMut<ActionQueue>,
     mut game_recorder: ResMut<GameRecorder>,
-    //mut rec_string: Local<String>,
+    (rules, economy, routes): (Res<GameSettings>, Res<Economy>, Res<SupplyRoutes>),
+    mut map_seed: ResMut<MapSeed>,
+    mut seed_string: Local<String>,
+    mut rec_string: Local<String>,
+    mut replay_error: Local<Option<String>>,
     mut player_last_dead: Local<bool>,
     outgoing_hats: Query<&Dropoff, With<OutgoingHats>>,
+    blobbies: Query<(Entity, &Blobby, &Orders, &BlobbyState)>,
 ) {
-    let mut _player_died_this_frame = false;
+    let mut player_died_this_frame = false;
     if !*player_last_dead && !player.alive() {
-        _player_died_this_frame = true;
+        player_died_this_frame = true;
         *player_last_dead = true;
+    } else if player.alive() {
+        *player_last_dead = false;
     }
 
     let window = windows.get_primary_mut().unwrap();
@@ -117,7 +142,7 @@ fn ui_sidebar(
         .default_width(window.width() * 0.17)
         .show_separator_line(false)
         .show(ctx.clone().ctx_mut(), |ui| {
-            let mut style = ui.style_mut();
+            let style = ui.style_mut();
             style.visuals.override_text_color = Some(TEXT_COLOR);
             style.visuals.widgets.active.bg_fill = DESELECTED_COLOR;
             style.visuals.widgets.inactive.bg_fill = DESELECTED_COLOR;
@@ -134,17 +159,20 @@ fn ui_sidebar(
                     }
                 }
 
-                let delivered_hats = outgoing_hats.single().input.0.get(&R::BigHats).unwrap();
+                let delivered_hats = outgoing_hats
+                    .iter()
+                    .map(|dropoff| dropoff.input.0.get(&R::BigHats).copied().unwrap_or(0))
+                    .sum::<u64>();
                 egui::Grid::new("DELIVERED grid").show(ui, |ui| {
                     ui.label(" HATS DELIVERED");
-                    ui.label(&format!("{}", delivered_hats));
+                    ui.label(format!("{}", delivered_hats));
                     ui.end_row();
                     ui.label(" HATS ORDERED ");
-                    ui.label(&format!("{}", player.required_hats));
+                    ui.label(format!("{}", player.required_hats));
                     ui.end_row();
                 });
                 ui.label(" DELIVERY DEADLINE");
-                ui.label(&format!(
+                ui.label(format!(
                     "{} SECONDS LEFT",
                     (player.delivery_dealine / 100.0) as i64
                 ));
@@ -156,20 +184,69 @@ fn ui_sidebar(
                 //    ui.end_row();
                 //});
 
-                if player.selected_entity.is_some() {
+                if !player.selected.is_empty() {
                     player.item_to_place = None;
                     player.sell_mode = false;
+                    player.upgrade_mode = false;
+                    player.recipe_mode = None;
+                }
+
+                let mut stuck = blobbies
+                    .iter()
+                    .filter(|(_, _, _, state)| **state == BlobbyState::Stuck)
+                    .map(|(entity, blobby, _, _)| (blobby.id, entity))
+                    .collect::<Vec<_>>();
+                stuck.sort();
+                if !stuck.is_empty() {
+                    ui.label("");
+                    ui.colored_label(WARNING_COLOR, "STUCK BLOBBIES");
+                    for (id, entity) in stuck {
+                        // Select it and follow it with the camera
+                        if ui.button(format!("BLOBBY{}", id)).clicked() {
+                            player.selected = vec![entity];
+                            player.focus = Some(entity);
+                            player.item_to_place = None;
+                            player.sell_mode = false;
+                            player.upgrade_mode = false;
+                            player.recipe_mode = None;
+                            player.route_mode = false;
+                        }
+                    }
+                }
+                if player.focus.is_some() && ui.button("VIEW WHOLE BOARD").clicked() {
+                    player.focus = None;
                 }
 
                 if player.alive() {
                     ui.label("");
                     ui.label("BUILD");
-                    ui_buy_button(&mut ctx, ui, "BLOBBY", Item::Blobby, &mut player);
+                    ui_buy_button(&mut ctx, ui, "BLOBBY", Item::Blobby, &mut player, &economy);
                     ui.label("");
                     ui.label("REFINERIES");
-                    ui_buy_button(&mut ctx, ui, "COPPER", Item::CopperRefinery, &mut player);
-                    ui_buy_button(&mut ctx, ui, "LITHIUM", Item::LithiumRefinery, &mut player);
-                    ui_buy_button(&mut ctx, ui, "GLASS", Item::GlassRefinery, &mut player);
+                    ui_buy_button(
+                        &mut ctx,
+                        ui,
+                        "COPPER",
+                        Item::CopperRefinery,
+                        &mut player,
+                        &economy,
+                    );
+                    ui_buy_button(
+                        &mut ctx,
+                        ui,
+                        "LITHIUM",
+                        Item::LithiumRefinery,
+                        &mut player,
+                        &economy,
+                    );
+                    ui_buy_button(
+                        &mut ctx,
+                        ui,
+                        "GLASS",
+                        Item::GlassRefinery,
+                        &mut player,
+                        &economy,
+                    );
                     ui.label("");
                     ui.label("FACTORIES");
                     ui_buy_button(
@@ -178,28 +255,167 @@ fn ui_sidebar(
                         "LITTLE HAT",
                         Item::LittleHatFactory,
                         &mut player,
+                        &economy,
+                    );
+                    ui_buy_button(
+                        &mut ctx,
+                        ui,
+                        "BATTERY",
+                        Item::BatteryFactory,
+                        &mut player,
+                        &economy,
                     );
-                    ui_buy_button(&mut ctx, ui, "BATTERY", Item::BatteryFactory, &mut player);
                     ui_buy_button(
                         &mut ctx,
                         ui,
                         "LIGHT BULB",
                         Item::LightbulbFactory,
                         &mut player,
+                        &economy,
                     );
-                    ui_buy_button(&mut ctx, ui, "BIG HAT", Item::BigHatFactory, &mut player);
+                    ui_buy_button(
+                        &mut ctx,
+                        ui,
+                        "BIG HAT",
+                        Item::BigHatFactory,
+                        &mut player,
+                        &economy,
+                    );
+                    ui_buy_button(
+                        &mut ctx,
+                        ui,
+                        "GENERIC",
+                        Item::Factory,
+                        &mut player,
+                        &economy,
+                    );
+                    if rules.blobby_drain > 0.0 {
+                        ui_buy_button(
+                            &mut ctx,
+                            ui,
+                            "CHARGING STATION",
+                            Item::ChargingStation,
+                            &mut player,
+                            &economy,
+                        );
+                    }
+                    if let Some(refused) = &player.placement_refused {
+                        ui.label("");
+                        ui.colored_label(WARNING_COLOR, refused.as_str());
+                    }
 
                     ui.label("");
                     if select_button(ui, "SELL", player.sell_mode).clicked() {
                         player.sell_mode = !player.sell_mode;
                         if player.sell_mode {
                             player.item_to_place = None;
-                            player.selected_entity = None;
+                            player.upgrade_mode = false;
+                            player.recipe_mode = None;
+                            player.selected.clear();
+                            player.route_mode = false;
+                        }
+                    }
+                    if select_button(ui, "UPGRADE", player.upgrade_mode).clicked() {
+                        player.upgrade_mode = !player.upgrade_mode;
+                        if player.upgrade_mode {
+                            player.item_to_place = None;
+                            player.sell_mode = false;
+                            player.recipe_mode = None;
+                            player.selected.clear();
+                            player.route_mode = false;
+                        }
+                    }
+                    ui.label("");
+
+                    // Picked here, then applied by clicking generic factories
+                    ui.label("FACTORY RECIPE");
+                    for kind in R::ALL {
+                        if !economy.recipes.contains_key(&kind) {
+                            continue;
+                        }
+                        let picked = player.recipe_mode == Some(kind);
+                        if select_button(ui, &kind.name(), picked).clicked() {
+                            player.recipe_mode = (!picked).then_some(kind);
+                            player.item_to_place = None;
+                            player.sell_mode = false;
+                            player.upgrade_mode = false;
+                            player.selected.clear();
+                            player.route_mode = false;
+                        }
+                    }
+                    ui.label("");
+
+                    ui.label("LOGISTICS");
+                    let selected = blobbies.iter_many(&player.selected).collect::<Vec<_>>();
+                    if let [(_, blobby, orders, _)] = selected[..] {
+                        let message = format!("AUTO BLOBBY{}", blobby.id);
+                        if select_button(ui, &message, blobby.auto).clicked() {
+                            action_queue.push(Action::SetAuto(!blobby.auto as u8, blobby.id));
+                        }
+                        // Orders are queued with shift click
+                        ui.label(format!("{} ORDERS QUEUED", orders.queue.len()));
+                        if select_button(ui, "PATROL", orders.patrol).clicked() {
+                            action_queue.push(Action::SetPatrol(!orders.patrol as u8, blobby.id));
+                        }
+                    } else if !selected.is_empty() {
+                        ui.label(format!("{} BLOBBIES SELECTED", selected.len()));
+                        let auto = selected.iter().all(|(_, blobby, _, _)| blobby.auto);
+                        if select_button(ui, "AUTO", auto).clicked() {
+                            for (_, blobby, _, _) in &selected {
+                                action_queue.push(Action::SetAuto(!auto as u8, blobby.id));
+                            }
+                        }
+                        let patrol = selected.iter().all(|(_, _, orders, _)| orders.patrol);
+                        if select_button(ui, "PATROL", patrol).clicked() {
+                            for (_, blobby, _, _) in &selected {
+                                action_queue.push(Action::SetPatrol(!patrol as u8, blobby.id));
+                            }
                         }
                     }
+                    if ui.button("SELECT IDLE").clicked() {
+                        player.selected = blobbies
+                            .iter()
+                            .filter(|(_, blobby, orders, _)| blobby.is_idle(orders))
+                            .map(|(entity, _, _, _)| entity)
+                            .collect();
+                        player.item_to_place = None;
+                        player.sell_mode = false;
+                        player.upgrade_mode = false;
+                        player.recipe_mode = None;
+                        player.route_mode = false;
+                    }
+                    if ui.button("ALL BLOBBIES AUTO").clicked() {
+                        action_queue.push(Action::SetAuto(1, 0));
+                    }
                     ui.label("");
 
-                    ui.label(&format!("GAME SPEED {:.2}", player.time_multiplier));
+                    ui.label("SUPPLY ROUTES");
+                    if select_button(ui, "EDIT ROUTES", player.route_mode).clicked() {
+                        player.route_mode = !player.route_mode;
+                        player.route_start = None;
+                        if player.route_mode {
+                            player.item_to_place = None;
+                            player.sell_mode = false;
+                            player.upgrade_mode = false;
+                            player.recipe_mode = None;
+                            player.selected.clear();
+                        }
+                    }
+                    if player.route_mode {
+                        ui.label(format!("NEW ROUTE PRIORITY {}", player.route_priority));
+                        ui.horizontal(|ui| {
+                            if ui.button(" -- ").clicked() {
+                                player.route_priority = (player.route_priority - 1).max(1);
+                            }
+                            if ui.button(" ++ ").clicked() {
+                                player.route_priority = (player.route_priority + 1).min(9);
+                            }
+                        });
+                    }
+                    ui.label(format!("{} ROUTES", routes.routes.len()));
+                    ui.label("");
+
+                    ui.label(format!("GAME SPEED {:.2}", player.time_multiplier));
                     ui.horizontal(|ui| {
                         if ui.button(" -- ").clicked() {
                             action_queue.push(Action::GameSpeedDec);
@@ -244,44 +460,56 @@ fn ui_sidebar(
                         pref.music = (pref.music + 0.1).min(3.0);
                         **audio_events |= MUSIC_LEVEL_CHANGED;
                     }
-                    ui.label(&format!("MUSIC {:.1}", pref.music));
+                    ui.label(format!("MUSIC {:.1}", pref.music));
                 });
                 ui.label("");
+                ui.label(format!("MAP SEED {}", map_seed.0));
+                ui.add(
+                    egui::TextEdit::singleline(&mut *seed_string).hint_text(map_seed.0.to_string()),
+                );
+                let mut restart = false;
                 if ui.button("RESTART GAME").clicked() {
+                    // An empty seed field restarts on the current map
+                    if let Ok(seed) = seed_string.trim().parse() {
+                        *map_seed = MapSeed(seed);
+                    }
+                    restart = true;
+                }
+                if ui.button("RANDOM MAP").clicked() {
+                    *map_seed = MapSeed::random();
+                    restart = true;
+                }
+                if restart {
+                    seed_string.clear();
                     action_queue.push(Action::RestartGame);
                     game_recorder.disable_rec = false;
                     game_recorder.play = false;
                     game_recorder.actions = ActionRecording::default();
+                    game_recorder.header = None;
                 }
-                /*
-                if select_button(ui, "REPLAY", game_recorder.play) {
+                if select_button(ui, "REPLAY", game_recorder.play).clicked() {
                     action_queue.push(Action::RestartGame);
                     game_recorder.play = true;
                     game_recorder.disable_rec = true;
                     game_recorder.play_head = 0;
                 }
                 if ui.text_edit_singleline(&mut *rec_string).changed() {
-                    if let Ok(compressed) = base64::decode(rec_string.trim()) {
-                        if let Ok(bytes) = decompress_size_prepended(&compressed) {
-                            if let Ok(archived) =
-                                rkyv::check_archived_root::<ActionRecording>(&bytes)
-                            {
-                                if let Ok(deserialized) =
-                                    archived.deserialize(&mut rkyv::Infallible)
-                                {
-                                    game_recorder.actions = deserialized;
-                                }
-                            }
+                    match ActionRecording::decode(&rec_string) {
+                        Ok((header, actions)) => {
+                            game_recorder.header = Some(header);
+                            game_recorder.actions = actions;
+                            *replay_error = None;
                         }
+                        Err(e) => *replay_error = Some(e.to_string().to_uppercase()),
                     }
                 }
+                if let Some(replay_error) = &*replay_error {
+                    ui.label(replay_error.as_str());
+                }
                 if player_died_this_frame || ui.button("GET REPLAY STRING").clicked() {
-                    let bytes = rkyv::to_bytes::<_, 1024>(&game_recorder.actions).unwrap();
-                    let compressed = compress_prepend_size(&bytes);
-                    let base64_bytes = base64::encode(compressed);
-                    *rec_string = base64_bytes;
+                    let header = ReplayHeader::new(map_seed.0, &rules, &economy);
+                    *rec_string = game_recorder.actions.encode(&header);
                 }
-                */
             });
         });
 }
@@ -306,7 +534,7 @@ fn ui_sidebar_left(
         .max_width(window.width() * 0.17)
         .show_separator_line(false)
         .show(ctx.ctx_mut(), |ui| {
-            let mut style = ui.style_mut();
+            let style = ui.style_mut();
             style.visuals.override_text_color = Some(TEXT_COLOR);
             style.visuals.widgets.active.bg_fill = DESELECTED_COLOR;
             style.visuals.widgets.inactive.bg_fill = DESELECTED_COLOR;
@@ -322,10 +550,69 @@ fn ui_sidebar_left(
         });
 }
 
+/// Draws supply routes as arrows from the pile to the building it feeds, labeled with their
+/// priority. Brighter while routes are being edited.
+fn ui_supply_routes(
+    mut ctx: ResMut<EguiContext>,
+    player: Res<PlayerState>,
+    routes: Res<SupplyRoutes>,
+    b: Res<GameBoard>,
+    windows: Res<Windows>,
+    cameras: Query<(&Camera, &GlobalTransform), With<Camera3d>>,
+    sites: Query<&Footprint>,
+) {
+    let (camera, camera_trans) = match cameras.iter().next() {
+        Some(camera) => camera,
+        None => return,
+    };
+    let height = match windows.get_primary() {
+        Some(window) => window.height(),
+        None => return,
+    };
+    // Viewport positions start at the bottom left, egui at the top left
+    let to_screen = |ws: Vec3| {
+        camera
+            .world_to_viewport(camera_trans, ws)
+            .map(|v| egui::pos2(v.x, height - v.y))
+    };
+    let color = if player.route_mode {
+        TEXT_COLOR
+    } else {
+        TEXT_COLOR2.linear_multiply(0.5)
+    };
+    let painter = ctx.ctx_mut().layer_painter(egui::LayerId::background());
+    for route in &routes.routes {
+        let ends = (sites.get(route.from), sites.get(route.to));
+        if let (Ok(from), Ok(to)) = ends {
+            let ends = (
+                to_screen(b.footprint_ws_vec3(from)),
+                to_screen(b.footprint_ws_vec3(to)),
+            );
+            if let (Some(from), Some(to)) = ends {
+                painter.arrow(from, to - from, egui::Stroke::new(2.0, color));
+                painter.text(
+                    from + (to - from) * 0.5,
+                    egui::Align2::CENTER_CENTER,
+                    route.priority,
+                    egui::FontId::proportional(16.0),
+                    color,
+                );
+            }
+        }
+    }
+    // The route being made follows the mouse
+    if let Some(start) = player.route_start {
+        let hover = ctx.ctx_mut().input().pointer.hover_pos();
+        if let (Some(from), Some(to)) = (to_screen(b.ls_to_ws_vec3(start)), hover) {
+            painter.line_segment([from, to], egui::Stroke::new(2.0, TEXT_COLOR));
+        }
+    }
+}
+
 pub fn setup_fonts(mut ctx: ResMut<EguiContext>) {
     let mut fonts = FontDefinitions::default();
 
-    for (_text_style, mut data) in fonts.font_data.iter_mut() {
+    for (_text_style, data) in fonts.font_data.iter_mut() {
         data.tweak.scale = 1.5;
         data.font =
             std::borrow::Cow::Borrowed(include_bytes!("../assets/fonts/ShareTechMono-Regular.ttf"));
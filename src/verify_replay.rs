@@ -0,0 +1,117 @@
+//! Plays back a replay without a window and prints how the game ended.
+//!
+//! Usage: verify_replay <REPLAY FILE OR STRING> [--max-steps N]
+//!
+//! Without `--max-steps` the replay runs until the player dies or one full delivery
+//! deadline has passed after the last recorded action.
+
+use std::{env, fs, process};
+
+use bevy::prelude::*;
+use lib_harvest::{
+    action::{ActionRecording, GameRecorder},
+    items::{Dropoff, OutgoingHats},
+    player::{PlayerState, R},
+    schedule::HarvestSimPlugin,
+    MapSeed,
+};
+
+fn usage() -> ! {
+    eprintln!("usage: verify_replay <REPLAY FILE OR STRING> [--max-steps N]");
+    process::exit(2)
+}
+
+fn main() {
+    let mut replay = None;
+    let mut max_steps = None;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--max-steps" {
+            max_steps = Some(
+                args.next()
+                    .and_then(|v| v.parse::<u64>().ok())
+                    .unwrap_or_else(|| usage()),
+            );
+        } else if replay.is_none() {
+            replay = Some(arg);
+        } else {
+            usage();
+        }
+    }
+    let replay = replay.unwrap_or_else(|| usage());
+    // Accept either a path to a file containing the replay string or the string itself
+    let replay = fs::read_to_string(&replay).unwrap_or(replay);
+
+    let (header, actions) = match ActionRecording::decode(&replay) {
+        Ok(decoded) => decoded,
+        Err(e) => {
+            eprintln!("error: {e}");
+            process::exit(1)
+        }
+    };
+    let economy = match header.economy() {
+        Ok(economy) => economy,
+        Err(e) => {
+            eprintln!("error: replay economy is invalid: {e}");
+            process::exit(1)
+        }
+    };
+    let max_steps = max_steps.unwrap_or(actions.last_step() + 50000);
+
+    println!("build version     {}", header.build_version);
+    println!("map seed          {}", header.seed);
+    println!(
+        "board size        {}x{}",
+        header.rules.board_size[0], header.rules.board_size[1]
+    );
+    println!("battery drain     {}", header.rules.blobby_drain);
+
+    let mut app = App::new();
+    app.add_plugins(MinimalPlugins)
+        .add_plugin(HarvestSimPlugin { headless: true })
+        .insert_resource(header.rules.clone())
+        .insert_resource(MapSeed(header.seed))
+        .insert_resource(economy)
+        .insert_resource(GameRecorder {
+            actions,
+            header: Some(header),
+            disable_rec: true,
+            play: true,
+            play_head: 0,
+        });
+
+    let mut died_at = None;
+    loop {
+        app.update();
+        let player = app.world.resource::<PlayerState>();
+        if !player.alive() {
+            died_at = Some(player.step);
+            break;
+        }
+        if player.step >= max_steps {
+            break;
+        }
+    }
+
+    let player = app.world.resource::<PlayerState>();
+    let final_step = player.step;
+    let combined_resources = player.combined_resources.clone();
+    let hats_delivered = app
+        .world
+        .query_filtered::<&Dropoff, With<OutgoingHats>>()
+        .iter(&app.world)
+        .map(|dropoff| dropoff.input.0.get(&R::BigHats).copied().unwrap_or(0))
+        .sum::<u64>();
+
+    println!("final step        {final_step}");
+    println!("hats delivered    {hats_delivered}");
+    match died_at {
+        Some(step) => println!("died at step      {step}"),
+        None => println!("died at step      - (still alive)"),
+    }
+    println!("resources");
+    for r in R::ALL {
+        let qty = combined_resources.0.get(&r).copied().unwrap_or(0);
+        println!("  {:<16}{qty}", r.name());
+    }
+}